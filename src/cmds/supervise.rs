@@ -1,16 +1,59 @@
-use crate::supervisor::Supervisor;
-use anyhow::Result;
+use crate::supervisor::{RetentionMode, Supervisor};
+use anyhow::{bail, Context, Result};
 use clap::Parser;
+use std::time::Duration;
 
 #[derive(Debug, Parser)]
 pub struct SuperviseCmd {
     #[clap(short, long)]
     database: String,
+
+    /// How to prune terminal tasks from the database:
+    ///
+    /// - `keep-all` (default) never prunes,
+    /// - `remove-succeeded:<secs>` drops succeeded tasks older than `<secs>`,
+    /// - `remove-terminal:<secs>` drops succeeded and failed tasks alike.
+    #[clap(long, default_value = "keep-all", value_parser = parse_retention)]
+    retention: RetentionMode,
+}
+
+fn parse_retention(s: &str) -> Result<RetentionMode> {
+    let (mode, rest) = match s.split_once(':') {
+        Some((mode, secs)) => (mode, Some(secs)),
+        None => (s, None),
+    };
+
+    let after = || -> Result<Duration> {
+        let secs = rest.with_context(|| {
+            format!("retention mode `{}` needs a `:<secs>` suffix", mode)
+        })?;
+
+        let secs = secs
+            .parse()
+            .with_context(|| format!("invalid retention age: {}", secs))?;
+
+        Ok(Duration::from_secs(secs))
+    };
+
+    match mode {
+        "keep-all" => Ok(RetentionMode::KeepAll),
+        "remove-succeeded" => {
+            Ok(RetentionMode::RemoveSucceeded { after: after()? })
+        }
+        "remove-terminal" => {
+            Ok(RetentionMode::RemoveTerminal { after: after()? })
+        }
+        other => bail!("unknown retention mode: {}", other),
+    }
 }
 
 impl SuperviseCmd {
     pub async fn run(self) -> Result<()> {
-        Supervisor::new(&self.database).await?.start().await?;
+        Supervisor::new(&self.database)
+            .await?
+            .with_retention(self.retention)
+            .start()
+            .await?;
 
         Ok(())
     }
@@ -1,7 +1,8 @@
 mod http;
 
+use crate::task::{Registry, WorkerLabels, WorkerQueues, DEFAULT_QUEUE};
 use crate::worker::{Worker, WorkerId};
-use anyhow::Result;
+use anyhow::{bail, Result};
 use clap::Parser;
 use std::future::IntoFuture;
 use std::net::SocketAddr;
@@ -20,15 +21,71 @@ pub struct WorkCmd {
 
     #[clap(short, long)]
     id: Uuid,
+
+    /// Capability labels this worker advertises, as `key=value` - tasks can
+    /// require a subset of these in order to be dispatched here.
+    #[clap(long = "label", value_parser = parse_label)]
+    labels: Vec<(String, String)>,
+
+    /// Named queues this worker services - it only picks up tasks whose queue
+    /// is in this set. Defaults to just the `default` queue when none given.
+    #[clap(long = "queue")]
+    queues: Vec<String>,
+
+    /// Throughput throttle: after each task the worker rests for this fraction
+    /// of the task's (windowed-average) duration before taking the next one, so
+    /// it doesn't saturate downstream systems. `0` (the default) disables it.
+    #[clap(long, default_value_t = 0.0)]
+    tranquility: f64,
+}
+
+fn parse_label(s: &str) -> Result<(String, String)> {
+    let Some((key, value)) = s.split_once('=') else {
+        bail!("expected a `key=value` label, got: {}", s);
+    };
+
+    Ok((key.to_owned(), value.to_owned()))
 }
 
 impl WorkCmd {
     pub async fn run(self) -> Result<()> {
-        let worker =
-            Worker::new(&self.database, WorkerId::new(self.id)).await?;
+        let labels: WorkerLabels = self.labels.into_iter().collect();
+
+        // An empty `--queue` list means "just the default queue", so a worker
+        // started without any queue flags keeps servicing ordinary tasks.
+        let queues: WorkerQueues = if self.queues.is_empty() {
+            WorkerQueues::from([DEFAULT_QUEUE.to_owned()])
+        } else {
+            self.queues.into_iter().collect()
+        };
+
+        // Build the shared application state once, at worker startup, and let
+        // every task borrow it through its `TaskContext` - here just a pooled
+        // HTTP client so tasks stop spinning up a fresh connection per run.
+        // Library users swap this for whatever their own tasks need.
+        let state = reqwest::Client::new();
+
+        // The registry maps stored `task_type` tags back to concrete runnables
+        // at dispatch time; out of the box it carries the bundled demo tasks,
+        // but library users register their own here. The HTTP server is handed
+        // the registered names so it can reject jobs no worker could run.
+        let registry = Registry::with_builtins();
+        let task_types = registry.task_types();
+
+        let worker = Worker::new(
+            &self.database,
+            WorkerId::new(self.id),
+            labels,
+            queues,
+            registry,
+            self.tranquility,
+            state,
+        )
+        .await?;
 
         let server =
-            http::serve(self.listen, worker.database().clone()).await?;
+            http::serve(self.listen, worker.database().clone(), task_types)
+                .await?;
 
         select! {
             result = worker.start() => result,
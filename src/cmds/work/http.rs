@@ -1,17 +1,43 @@
 mod endpoints;
 
 use anyhow::Result;
+use axum::extract::FromRef;
 use axum::routing::{get, post};
 use axum::serve::Serve;
 use axum::Router;
 use sqlx::PgPool;
+use std::collections::BTreeSet;
 use std::net::SocketAddr;
+use std::sync::Arc;
 use tokio::net::TcpListener;
 use tracing::info;
 
+/// Shared state threaded into every endpoint. Endpoints that only touch the
+/// database keep extracting `State<PgPool>` thanks to the `FromRef` impls
+/// below; `create_task` additionally pulls the set of registered task types to
+/// reject unknown kinds up front.
+#[derive(Clone)]
+pub struct AppState {
+    database: PgPool,
+    task_types: Arc<BTreeSet<String>>,
+}
+
+impl FromRef<AppState> for PgPool {
+    fn from_ref(state: &AppState) -> Self {
+        state.database.clone()
+    }
+}
+
+impl FromRef<AppState> for Arc<BTreeSet<String>> {
+    fn from_ref(state: &AppState) -> Self {
+        state.task_types.clone()
+    }
+}
+
 pub async fn serve(
     addr: SocketAddr,
     database: PgPool,
+    task_types: BTreeSet<String>,
 ) -> Result<Serve<Router, Router>> {
     use self::endpoints::*;
 
@@ -19,6 +45,11 @@ pub async fn serve(
 
     let listener = TcpListener::bind(addr).await?;
 
+    let state = AppState {
+        database,
+        task_types: Arc::new(task_types),
+    };
+
     let router = Router::new()
         .route(
             "/tasks",
@@ -28,7 +59,7 @@ pub async fn serve(
             "/tasks/:id",
             get(get_task::endpoint).delete(delete_task::endpoint),
         )
-        .with_state(database);
+        .with_state(state);
 
     info!(?addr, "ready");
 
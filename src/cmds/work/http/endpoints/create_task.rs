@@ -1,26 +1,85 @@
 use crate::database::Database;
 use crate::supervisor::SupervisorNotification;
-use crate::task::{TaskDef, TaskId};
+use crate::task::{
+    BackoffPolicy, StoredTask, TaskId, TaskRequirements, DEFAULT_QUEUE,
+};
 use axum::extract::State;
-use axum::response::IntoResponse;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
 use axum::Json;
 use chrono::{DateTime, Utc};
+use cron::Schedule;
 use serde::{Deserialize, Serialize};
 use sqlx::PgPool;
+use std::collections::BTreeSet;
+use std::str::FromStr;
+use std::sync::Arc;
 
 pub async fn endpoint(
     State(db): State<PgPool>,
+    State(task_types): State<Arc<BTreeSet<String>>>,
     Json(req): Json<Request>,
-) -> impl IntoResponse {
+) -> Response {
+    // Reject jobs for kinds no worker has registered up front, rather than
+    // letting them sit in the queue only to fail at dispatch time.
+    if !task_types.contains(&req.def.task_type) {
+        return (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            format!("unknown task type: {}", req.def.task_type),
+        )
+            .into_response();
+    }
+
+    // Parse-check the cron expression here, while we can still tell the caller
+    // about it - an unparseable schedule stored now would only blow up later,
+    // on the supervisor, when it tries to compute the task's next occurrence.
+    if let Some(schedule) = &req.schedule {
+        if let Err(err) = Schedule::from_str(schedule) {
+            return (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                format!("invalid cron schedule: {}", err),
+            )
+                .into_response();
+        }
+    }
+
     // TODO handle .unwrap()
     let tx = db.begin().await.unwrap();
 
-    let id = Database::create_task(&db, req.def, Utc::now(), req.scheduled_at)
-        .await
-        .unwrap();
+    let created_at = Utc::now();
+
+    // Opt-in deduplication: only hash the definition when the caller asked for
+    // it, so ordinary tasks keep inserting unconditionally.
+    let uniq_hash = if req.unique {
+        Some(req.def.uniq_hash(req.uniq_key.as_deref()).unwrap())
+    } else {
+        None
+    };
+
+    let id = Database::create_task(
+        &db,
+        req.def,
+        req.priority,
+        req.requires,
+        req.queue,
+        req.schedule,
+        None,
+        req.max_retries,
+        req.retry_policy,
+        req.retry_base_secs,
+        req.retry_max_backoff_secs,
+        req.timeout_secs,
+        uniq_hash,
+        created_at,
+        req.scheduled_at,
+    )
+    .await
+    .unwrap();
 
     SupervisorNotification::TaskCreated {
         id,
+        priority: req.priority,
+        created_at,
         scheduled_at: req.scheduled_at,
     }
     .send(&db)
@@ -29,17 +88,98 @@ pub async fn endpoint(
 
     tx.commit().await.unwrap();
 
-    Json(Response { id })
+    Json(CreateTaskResponse { id }).into_response()
 }
 
 #[derive(Clone, Debug, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct Request {
-    def: TaskDef,
+    /// Tagged JSON describing the job: a registered `task_type` plus its
+    /// serialized `payload`. Arbitrary user-defined kinds are accepted as long
+    /// as a worker has registered the type.
+    def: StoredTask,
+
+    /// Dispatch priority - among tasks that are ready at the same moment, the
+    /// ones with a higher priority get dispatched first. Defaults to `0`, like
+    /// remote-execution schedulers' default execution priority.
+    #[serde(default)]
+    priority: i32,
+
+    /// Worker labels this task requires - a value of `null` means the worker
+    /// just has to advertise the key, any other value must match exactly.
+    #[serde(default)]
+    requires: TaskRequirements,
+
+    /// Named queue this task lands on - only workers subscribed to it will pick
+    /// it up. Defaults to the `default` queue.
+    #[serde(default = "default_queue")]
+    queue: String,
+
+    /// Optional cron expression turning this into a recurring task - once it
+    /// completes, the supervisor re-enqueues it at its next fire time. Uses the
+    /// `cron` crate's seconds-resolution syntax: 6 fields
+    /// (`sec min hour day-of-month month day-of-week`), or 7 with an optional
+    /// trailing year. Rejected with `422` if it doesn't parse.
+    #[serde(default)]
+    schedule: Option<String>,
+
+    /// How many times a failed/interrupted task may be retried before it lands
+    /// in the terminal `failed` state.
+    #[serde(default = "default_max_retries")]
+    max_retries: i32,
+
+    /// How the delay between retries grows - fixed, linear or exponential.
+    /// Defaults to exponential.
+    #[serde(default)]
+    retry_policy: BackoffPolicy,
+
+    /// Base delay (in seconds) for the retry backoff.
+    #[serde(default = "default_retry_base_secs")]
+    retry_base_secs: i64,
+
+    /// Upper bound (in seconds) the exponential retry backoff is clamped to.
+    #[serde(default = "default_retry_max_backoff_secs")]
+    retry_max_backoff_secs: i64,
+
+    /// Optional wall-clock execution budget (in seconds) - a task still running
+    /// past this point is cancelled on its worker and routed back through the
+    /// retry path, even if the worker is otherwise alive and heartbeating.
+    #[serde(default)]
+    timeout_secs: Option<i64>,
+
+    /// Opt into idempotent enqueue: while an identical task is still in flight,
+    /// re-submitting it returns the existing task's id instead of running it
+    /// again.
+    #[serde(default)]
+    unique: bool,
+
+    /// Extra salt mixed into the uniqueness hash (only meaningful with
+    /// `unique`), letting callers widen or narrow what counts as "identical".
+    #[serde(default)]
+    uniq_key: Option<String>,
+
     scheduled_at: Option<DateTime<Utc>>,
 }
 
+fn default_queue() -> String {
+    DEFAULT_QUEUE.to_owned()
+}
+
+fn default_max_retries() -> i32 {
+    3
+}
+
+fn default_retry_base_secs() -> i64 {
+    1
+}
+
+fn default_retry_max_backoff_secs() -> i64 {
+    // 5 minutes - long enough to ride out a transient outage, short enough not
+    // to park a task for ages
+    5 * 60
+}
+
 #[derive(Clone, Debug, Serialize)]
-pub struct Response {
+pub struct CreateTaskResponse {
     id: TaskId,
 }
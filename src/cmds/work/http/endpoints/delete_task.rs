@@ -2,6 +2,7 @@ use crate::database::Database;
 use crate::task::TaskId;
 use axum::extract::{Path, State};
 use axum::response::IntoResponse;
+use chrono::Utc;
 use sqlx::PgPool;
 use uuid::Uuid;
 
@@ -10,5 +11,7 @@ pub async fn endpoint(
     Path(id): Path<Uuid>,
 ) -> impl IntoResponse {
     // TODO .unwrap()
-    Database::delete_task(&db, TaskId::new(id)).await.unwrap();
+    Database::delete_task(&db, TaskId::new(id), Utc::now())
+        .await
+        .unwrap();
 }
@@ -1,4 +1,6 @@
-use crate::task::{Task, TaskDef, TaskId, TaskStatus};
+use crate::task::{
+    BackoffPolicy, StoredTask, Task, TaskId, TaskRequirements, TaskStatus,
+};
 use crate::worker::WorkerId;
 use anyhow::{Context, Result};
 use chrono::{DateTime, Utc};
@@ -79,7 +81,18 @@ impl Database {
     #[instrument(skip(db))]
     pub async fn create_task(
         db: impl Executor<'_, Database = Postgres>,
-        def: TaskDef,
+        def: StoredTask,
+        priority: i32,
+        requires: TaskRequirements,
+        queue: String,
+        schedule: Option<String>,
+        series_id: Option<TaskId>,
+        max_retries: i32,
+        retry_policy: BackoffPolicy,
+        retry_base_secs: i64,
+        retry_max_backoff_secs: i64,
+        timeout_secs: Option<i64>,
+        uniq_hash: Option<String>,
         created_at: DateTime<Utc>,
         scheduled_at: Option<DateTime<Utc>>,
     ) -> Result<TaskId> {
@@ -91,35 +104,97 @@ impl Database {
         //      (it'll cause the query to throw "duplicate key violates ...")
         let id = TaskId::new(Uuid::new_v4());
 
-        sqlx::query(
+        // A recurring task is the head of a series - reschedules carry their
+        // parent's `series_id`, while the first task in a series points at
+        // itself.
+        let series_id = series_id.unwrap_or(id);
+
+        // When `uniq_hash` is set we want an idempotent enqueue: a partial
+        // unique index over the non-terminal statuses means a second identical
+        // task collides, and we hand the caller back the id of the task that's
+        // already in flight instead of inserting a duplicate. A `null` hash
+        // never collides, so un-deduplicated tasks always insert.
+        let row = sqlx::query(
             "
-            insert into tasks (
-                id,
-                def,
-                worker_id,
-                status,
-                created_at,
-                updated_at,
-                scheduled_at
-            ) values (
-                $1,
-                $2,
-                null,
-                'pending',
-                $3,
-                $3,
-                $4
+            with ins as (
+                insert into tasks (
+                    id,
+                    def,
+                    worker_id,
+                    status,
+                    priority,
+                    requires,
+                    queue,
+                    recurring,
+                    schedule,
+                    series_id,
+                    attempts,
+                    max_retries,
+                    retry_policy,
+                    retry_base_secs,
+                    retry_max_backoff_secs,
+                    timeout_secs,
+                    uniq_hash,
+                    created_at,
+                    updated_at,
+                    scheduled_at
+                ) values (
+                    $1,
+                    $2,
+                    null,
+                    'pending',
+                    $3,
+                    $4,
+                    $16,
+                    $5,
+                    $6,
+                    $7,
+                    0,
+                    $8,
+                    $9,
+                    $10,
+                    $11,
+                    $12,
+                    $13,
+                    $14,
+                    $14,
+                    $15
+                )
+                on conflict (uniq_hash)
+                    where status in ('pending', 'dispatched', 'running')
+                    do nothing
+                returning id
             )
+            select id from ins
+            union all
+            select id
+              from tasks
+             where uniq_hash = $13
+               and status in ('pending', 'dispatched', 'running')
+               and not exists (select 1 from ins)
+             limit 1
             ",
         )
         .bind(id.get())
         .bind(Json(&def))
+        .bind(priority)
+        .bind(Json(&requires))
+        .bind(schedule.is_some())
+        .bind(schedule)
+        .bind(series_id.get())
+        .bind(max_retries)
+        .bind(retry_policy)
+        .bind(retry_base_secs)
+        .bind(retry_max_backoff_secs)
+        .bind(timeout_secs)
+        .bind(uniq_hash)
         .bind(created_at)
         .bind(scheduled_at)
-        .execute(db)
+        .bind(queue)
+        .fetch_one(db)
         .await?;
 
-        Ok(id)
+        Ok(TaskId::new(row.get(0)))
     }
 
     #[instrument(skip(db))]
@@ -136,6 +211,7 @@ impl Database {
                 update tasks
                    set worker_id = $1,
                        status = 'dispatched',
+                       dispatched_at = $2,
                        updated_at = $2
                  where id = $3
                    and status = 'pending'
@@ -156,7 +232,7 @@ impl Database {
         db: impl Executor<'_, Database = Postgres>,
         id: TaskId,
         updated_at: DateTime<Utc>,
-    ) -> Result<TaskDef> {
+    ) -> Result<StoredTask> {
         trace!("running query");
 
         let row = sqlx::query(
@@ -208,27 +284,127 @@ impl Database {
         Ok(())
     }
 
+    /// Marks a task held by a now-dead (or misbehaving) worker as
+    /// `interrupted`, so it can be routed through the retry path.
+    #[instrument(skip(db))]
+    pub async fn interrupt_task(
+        db: impl Executor<'_, Database = Postgres>,
+        id: TaskId,
+        updated_at: DateTime<Utc>,
+    ) -> Result<()> {
+        trace!("running query");
+
+        sqlx::query(
+            "
+            update tasks
+               set status = 'interrupted',
+                   updated_at = $1
+             where id = $2
+               and status in ('dispatched', 'running')
+            ",
+        )
+        .bind(updated_at)
+        .bind(id.get())
+        .execute(db)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Re-arms an interrupted/failed task for another attempt: bumps the
+    /// attempt counter, drops the worker assignment and puts it back into the
+    /// `pending` pool with a (future) `scheduled_at` computed from the backoff.
+    #[instrument(skip(db))]
+    pub async fn retry_task(
+        db: impl Executor<'_, Database = Postgres>,
+        id: TaskId,
+        attempts: i32,
+        scheduled_at: DateTime<Utc>,
+        updated_at: DateTime<Utc>,
+    ) -> Result<()> {
+        trace!("running query");
+
+        sqlx::query(
+            "
+            update tasks
+               set status = 'pending',
+                   worker_id = null,
+                   attempts = $1,
+                   scheduled_at = $2,
+                   updated_at = $3
+             where id = $4
+               and status in ('running', 'interrupted')
+            ",
+        )
+        .bind(attempts)
+        .bind(scheduled_at)
+        .bind(updated_at)
+        .bind(id.get())
+        .execute(db)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Soft-deletes a task: stamps `deleted_at` so it drops out of
+    /// `find_tasks`/`get_backlog` while staying on disk for auditing. Hard
+    /// pruning for throughput lives in `prune_tasks`.
     #[instrument(skip(db))]
     pub async fn delete_task(
         db: impl Executor<'_, Database = Postgres>,
         id: TaskId,
+        deleted_at: DateTime<Utc>,
     ) -> Result<()> {
         trace!("running query");
 
-        // TODO consider soft-deletions
         sqlx::query(
             "
-            delete from tasks
-                  where id = $1
+            update tasks
+               set deleted_at = $2
+             where id = $1
+               and deleted_at is null
             ",
         )
         .bind(id.get())
+        .bind(deleted_at)
         .execute(db)
         .await?;
 
         Ok(())
     }
 
+    /// Hard-deletes terminal tasks last touched before `before` whose status is
+    /// in `statuses`, returning how many rows were removed.
+    ///
+    /// This is the throughput counterpart to `delete_task`'s soft-delete: it
+    /// actually reclaims the rows so `find_tasks`/`get_backlog` don't keep
+    /// scanning an ever-growing table. The supervisor drives it periodically
+    /// according to its `RetentionMode`. Rows already soft-deleted (a non-null
+    /// `deleted_at`) are left in place so they stay available as an audit trail.
+    #[instrument(skip(db))]
+    pub async fn prune_tasks(
+        db: impl Executor<'_, Database = Postgres>,
+        before: DateTime<Utc>,
+        statuses: &[TaskStatus],
+    ) -> Result<u64> {
+        trace!("running query");
+
+        let result = sqlx::query(
+            "
+            delete from tasks
+                  where updated_at < $1
+                    and status = any($2)
+                    and deleted_at is null
+            ",
+        )
+        .bind(before)
+        .bind(statuses)
+        .execute(db)
+        .await?;
+
+        Ok(result.rows_affected())
+    }
+
     #[instrument(skip(db))]
     pub async fn find_task(
         db: impl Executor<'_, Database = Postgres>,
@@ -255,12 +431,25 @@ impl Database {
                    def,
                    worker_id,
                    status,
+                   priority,
+                   requires,
+                   queue,
+                   schedule,
+                   series_id,
+                   attempts,
+                   max_retries,
+                   retry_policy,
+                   retry_base_secs,
+                   retry_max_backoff_secs,
+                   timeout_secs,
+                   uniq_hash,
                    created_at,
                    updated_at,
                    scheduled_at
               from tasks
              where ($1 is null or id = $1)
                and ($2 is null or status = $2)
+               and deleted_at is null
             ",
         )
         .bind(id.map(|id| id.get()))
@@ -270,9 +459,21 @@ impl Database {
             def: row.get::<Json<_>, _>(1).0,
             worker_id: row.get::<Option<_>, _>(2).map(WorkerId::new),
             status: row.get(3),
-            created_at: row.get(4),
-            updated_at: row.get(5),
-            scheduled_at: row.get(6),
+            priority: row.get(4),
+            requires: row.get::<Json<_>, _>(5).0,
+            queue: row.get(6),
+            schedule: row.get(7),
+            series_id: row.get::<Option<_>, _>(8).map(TaskId::new),
+            attempts: row.get(9),
+            max_retries: row.get(10),
+            retry_policy: row.get(11),
+            retry_base_secs: row.get(12),
+            retry_max_backoff_secs: row.get(13),
+            timeout_secs: row.get(14),
+            uniq_hash: row.get(15),
+            created_at: row.get(16),
+            updated_at: row.get(17),
+            scheduled_at: row.get(18),
         })
         .fetch_all(db)
         .await?;
@@ -280,20 +481,177 @@ impl Database {
         Ok(tasks)
     }
 
+    /// Reaps workers that haven't been heard from since `cutoff`: marks their
+    /// in-flight (`dispatched` or `running`) tasks `interrupted`, returns those task ids so the caller
+    /// can route them through the retry path, and deletes the worker rows so
+    /// node discovery stays clean.
+    ///
+    /// This is the database-side counterpart to `SupervisedWorkers::gc` - the
+    /// latter only knows about workers that have reported to *this* supervisor
+    /// since it started, whereas this catches tasks stranded by a worker that
+    /// died before the supervisor came up.
+    #[instrument(skip(db))]
+    pub async fn reap_stale_workers(
+        db: impl Executor<'_, Database = Postgres>,
+        cutoff: DateTime<Utc>,
+        updated_at: DateTime<Utc>,
+    ) -> Result<Vec<TaskId>> {
+        trace!("running query");
+
+        let rows = sqlx::query(
+            "
+            with stale as (
+                select id from workers where last_heard_at < $1
+            ),
+            reaped as (
+                update tasks
+                   set status = 'interrupted',
+                       worker_id = null,
+                       updated_at = $2
+                 where worker_id in (select id from stale)
+                   and status in ('dispatched', 'running')
+             returning id
+            ),
+            deleted as (
+                delete from workers where id in (select id from stale)
+            )
+            select id from reaped
+            ",
+        )
+        .bind(cutoff)
+        .bind(updated_at)
+        .map(|row: PgRow| TaskId::new(row.get(0)))
+        .fetch_all(db)
+        .await?;
+
+        Ok(rows)
+    }
+
+    /// Reclaims tasks that were dispatched to a worker that never acknowledged
+    /// them (they never transitioned to `running`) within the ack window: resets
+    /// them to `pending`, drops the stale worker assignment and returns their
+    /// scheduling tuples so the caller can re-arm them in `PendingTasks`.
+    ///
+    /// `cutoff` is the oldest `dispatched_at` a task may carry and still be
+    /// considered in-flight; anything older is treated as a lost dispatch.
+    #[instrument(skip(db))]
+    pub async fn reap_stuck_dispatched(
+        db: impl Executor<'_, Database = Postgres>,
+        cutoff: DateTime<Utc>,
+        updated_at: DateTime<Utc>,
+    ) -> Result<Vec<(TaskId, Option<DateTime<Utc>>, i32, DateTime<Utc>)>> {
+        trace!("running query");
+
+        let rows = sqlx::query(
+            "
+            with stuck as (
+                update tasks
+                   set status = 'pending',
+                       worker_id = null,
+                       updated_at = $2
+                 where status = 'dispatched'
+                   and dispatched_at < $1
+             returning id, scheduled_at, priority, created_at
+            )
+            select id, scheduled_at, priority, created_at from stuck
+            ",
+        )
+        .bind(cutoff)
+        .bind(updated_at)
+        .map(|row: PgRow| {
+            (TaskId::new(row.get(0)), row.get(1), row.get(2), row.get(3))
+        })
+        .fetch_all(db)
+        .await?;
+
+        Ok(rows)
+    }
+
+    /// Finds recurring series that have no outstanding instance, returning one
+    /// representative task id per such series.
+    ///
+    /// A recurring task normally re-arms itself when it completes (see
+    /// `reschedule_recurring`), but if the supervisor dies in the window between
+    /// a task finishing and that reschedule, the whole series would silently
+    /// stop. On startup we use this to catch those up: the representative is the
+    /// most recently touched task of the series, so the caller can compute the
+    /// next fire time from its schedule - one occurrence, not every missed slot.
+    #[instrument(skip(db))]
+    pub async fn find_due_recurring(
+        db: impl Executor<'_, Database = Postgres>,
+    ) -> Result<Vec<TaskId>> {
+        trace!("running query");
+
+        let rows = sqlx::query(
+            "
+            select distinct on (series_id) id
+              from tasks t
+             where recurring = true
+               and deleted_at is null
+               and not exists (
+                   select 1
+                     from tasks o
+                    where o.series_id = t.series_id
+                      and o.status in ('pending', 'dispatched', 'running')
+               )
+             order by series_id, updated_at desc
+            ",
+        )
+        .map(|row: PgRow| TaskId::new(row.get(0)))
+        .fetch_all(db)
+        .await?;
+
+        Ok(rows)
+    }
+
     #[instrument(skip(db))]
     pub async fn get_backlog(
         db: impl Executor<'_, Database = Postgres>,
-    ) -> Result<Vec<(TaskId, Option<DateTime<Utc>>)>> {
+    ) -> Result<Vec<(TaskId, Option<DateTime<Utc>>, i32, DateTime<Utc>)>> {
+        trace!("running query");
+
+        let rows = sqlx::query(
+            "
+            select id, scheduled_at, priority, created_at
+              from tasks
+             where status = 'pending'
+               and deleted_at is null
+            ",
+        )
+        .map(|row: PgRow| {
+            (TaskId::new(row.get(0)), row.get(1), row.get(2), row.get(3))
+        })
+        .fetch_all(db)
+        .await?;
+
+        Ok(rows)
+    }
+
+    /// Returns (up to) the `limit` soonest-due pending tasks, used to refill the
+    /// in-memory window after it drains a task or evicts beyond its capacity.
+    ///
+    /// The ordering mirrors `PendingTasks`' own dispatch order: due time first
+    /// (with unscheduled tasks treated as due now), then priority, then age.
+    pub async fn get_pending_window(
+        db: impl Executor<'_, Database = Postgres>,
+        limit: i64,
+    ) -> Result<Vec<(TaskId, Option<DateTime<Utc>>, i32, DateTime<Utc>)>> {
         trace!("running query");
 
         let rows = sqlx::query(
             "
-            select id, scheduled_at
+            select id, scheduled_at, priority, created_at
               from tasks
              where status = 'pending'
+               and deleted_at is null
+             order by scheduled_at nulls first, priority desc, created_at
+             limit $1
             ",
         )
-        .map(|row: PgRow| (TaskId::new(row.get(0)), row.get(1)))
+        .bind(limit)
+        .map(|row: PgRow| {
+            (TaskId::new(row.get(0)), row.get(1), row.get(2), row.get(3))
+        })
         .fetch_all(db)
         .await?;
 
@@ -304,12 +662,21 @@ impl Database {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::task::TaskStatus;
+    use crate::task::{TaskStatus, DEFAULT_QUEUE};
     use crate::test_utils::dt;
     use futures::future::BoxFuture;
     use sqlx::Transaction;
     use test_case::test_case;
 
+    /// The built-in `bar` task with an empty payload, the stand-in the DB tests
+    /// round-trip through the `def` column.
+    fn bar() -> StoredTask {
+        StoredTask {
+            task_type: "bar".to_owned(),
+            payload: serde_json::Value::Null,
+        }
+    }
+
     impl Database {
         pub async fn test() -> PgPool {
             Self::connect("postgres://127.0.0.1:5432/db")
@@ -404,13 +771,28 @@ mod tests {
     async fn create_task() {
         Database::with_test(|mut tx| {
             Box::pin(async move {
-                let def = TaskDef::Bar;
+                let def = StoredTask {
+                    task_type: "bar".to_owned(),
+                    payload: serde_json::Value::Null,
+                };
+                let priority = 7;
                 let created_at = dt("2018-01-01 12:00:00");
                 let scheduled_at = Some(dt("2018-01-02 10:00:00"));
 
                 let id = Database::create_task(
                     &mut *tx,
-                    def,
+                    def.clone(),
+                    priority,
+                    TaskRequirements::new(),
+                    DEFAULT_QUEUE.to_owned(),
+                    None,
+                    None,
+                    3,
+                    BackoffPolicy::default(),
+                    1,
+                    300,
+                    None,
+                    None,
                     created_at,
                     scheduled_at,
                 )
@@ -422,6 +804,12 @@ mod tests {
                 assert_eq!(def, actual.def);
                 assert_eq!(None, actual.worker_id);
                 assert_eq!(TaskStatus::Pending, actual.status);
+                assert_eq!(priority, actual.priority);
+                assert_eq!(TaskRequirements::new(), actual.requires);
+                assert_eq!(DEFAULT_QUEUE, actual.queue);
+                assert_eq!(None, actual.schedule);
+                // A non-recurring task still heads its own (degenerate) series
+                assert_eq!(Some(id), actual.series_id);
                 assert_eq!(created_at, actual.created_at);
                 assert_eq!(created_at, actual.updated_at);
                 assert_eq!(scheduled_at, actual.scheduled_at);
@@ -446,7 +834,7 @@ mod tests {
                 // ---
 
                 let task_id =
-                    Database::create_task(&mut *tx, TaskDef::Bar, now, None)
+                    Database::create_task(&mut *tx, bar(), 0, TaskRequirements::new(), DEFAULT_QUEUE.to_owned(), None, None, 3, BackoffPolicy::default(), 1, 300, None, None, now, None)
                         .await
                         .unwrap();
 
@@ -471,7 +859,7 @@ mod tests {
                 let actual_status =
                     Database::get_task_status(&mut *tx, task_id).await;
 
-                assert_eq!(TaskDef::Bar, actual_def);
+                assert_eq!(bar(), actual_def);
                 assert_eq!(TaskStatus::Running, actual_status);
 
                 // ---
@@ -510,7 +898,7 @@ mod tests {
                 // ---
 
                 let task_id =
-                    Database::create_task(&mut *tx, TaskDef::Bar, now, None)
+                    Database::create_task(&mut *tx, bar(), 0, TaskRequirements::new(), DEFAULT_QUEUE.to_owned(), None, None, 3, BackoffPolicy::default(), 1, 300, None, None, now, None)
                         .await
                         .unwrap();
 
@@ -8,14 +8,40 @@ pub use self::notification::*;
 use self::tasks::*;
 use self::workers::*;
 use crate::database::Database;
+use crate::task::{TaskId, TaskStatus};
 use crate::worker::WorkerNotification;
+use crate::HEARTBEAT_TIMEOUT;
 use anyhow::{Context, Result};
-use chrono::Utc;
+use chrono::{DateTime, Utc};
 use sqlx::PgPool;
+use std::collections::BTreeSet;
 use std::time::Duration;
-use tokio::time::Interval;
+use tokio::time::{Instant, Interval};
 use tokio::{select, time};
-use tracing::{debug, info};
+use tracing::{debug, info, warn};
+
+/// How many soonest-due tasks the supervisor keeps resident in memory before it
+/// starts leaning on lazy refetches from the database; see `PendingTasks`.
+const TASK_WINDOW: usize = 1024;
+
+/// How often we sample the wall clock against a monotonic baseline to notice the
+/// OS clock jumping out from under the pending tasks' deadlines.
+const CLOCK_SAMPLE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How far the wall clock may drift from where the monotonic clock expects it
+/// before we treat it as a jump and re-arm every pending task. Comfortably
+/// above the jitter of a healthy NTP slew, below anything a human would notice.
+const CLOCK_JUMP_THRESHOLD: Duration = Duration::from_secs(5);
+
+/// How long a task may sit in `dispatched` without a worker picking it up
+/// (transitioning it to `running`) before the supervisor assumes the dispatch
+/// was lost and re-pends the task for another worker.
+const DISPATCH_ACK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How often the supervisor sweeps terminal tasks out of the database according
+/// to its `RetentionMode`. Coarser than the 1s maintenance tick - reclaiming
+/// disk isn't latency-sensitive.
+const RETENTION_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
 
 #[derive(Debug)]
 pub struct Supervisor {
@@ -24,6 +50,9 @@ pub struct Supervisor {
     workers: SupervisedWorkers,
     tasks: PendingTasks,
     maintenance: Interval,
+    clock: ClockMonitor,
+    retention: Interval,
+    retention_mode: RetentionMode,
 }
 
 impl Supervisor {
@@ -47,19 +76,37 @@ impl Supervisor {
             database,
             listener,
             workers,
-            tasks: Default::default(),
+            tasks: PendingTasks::new(TASK_WINDOW),
             maintenance: time::interval(Duration::from_secs(1)),
+            clock: ClockMonitor::new(),
+            retention: time::interval(RETENTION_SWEEP_INTERVAL),
+            retention_mode: RetentionMode::default(),
         })
     }
 
+    /// Sets how aggressively terminal tasks are pruned from the database; see
+    /// `RetentionMode`. Defaults to `KeepAll`.
+    pub fn with_retention(mut self, mode: RetentionMode) -> Self {
+        self.retention_mode = mode;
+        self
+    }
+
     pub async fn start(&mut self) -> Result<()> {
         self.process_backlog()
             .await
             .context("couldn't process backlog")?;
 
+        self.rearm_recurring()
+            .await
+            .context("couldn't re-arm recurring tasks")?;
+
         info!("ready");
 
         loop {
+            // Snapshot the nearest running-task timeout before borrowing `self`
+            // in the `select!` below.
+            let timeout_at = self.workers.next_timeout();
+
             let reason = select! {
                 notif = self.listener.next() => {
                     WakeupReason::GotNotification(notif)
@@ -67,35 +114,96 @@ impl Supervisor {
                 id = &mut self.tasks => {
                     WakeupReason::GotTask(id)
                 },
+                _ = Self::sleep_until(timeout_at) => {
+                    WakeupReason::TaskTimedOut
+                },
                 _ = self.maintenance.tick() => {
                     WakeupReason::MaintenanceTime
+                },
+                _ = self.clock.tick() => {
+                    WakeupReason::ClockSample
+                },
+                _ = self.retention.tick() => {
+                    WakeupReason::RetentionSweep
                 }
             };
 
             match reason {
                 WakeupReason::GotNotification(notif) => match notif? {
-                    SupervisorNotification::WorkerHeartbeat { id, status } => {
-                        self.workers.add(id, status, Utc::now());
-                        self.tasks.resume();
+                    SupervisorNotification::WorkerHeartbeat {
+                        id,
+                        status,
+                        labels,
+                        queues,
+                        task_types,
+                    } => {
+                        self.workers.add(
+                            id, status, labels, queues, task_types,
+                            Utc::now(),
+                        );
+                        self.tasks.resume(Utc::now());
                     }
 
                     SupervisorNotification::WorkerIdle { id } => {
                         self.workers.mark_as_idle(id);
-                        self.tasks.resume();
+                        self.tasks.resume(Utc::now());
                     }
 
                     SupervisorNotification::TaskCreated {
                         id,
+                        priority,
+                        created_at,
                         scheduled_at,
                     } => {
-                        self.tasks.push(id, scheduled_at, Utc::now());
+                        self.tasks.push(
+                            id,
+                            scheduled_at,
+                            priority,
+                            created_at,
+                            Utc::now(),
+                        );
+                    }
+
+                    SupervisorNotification::TaskCompleted { id, succeeded } => {
+                        if succeeded {
+                            self.reschedule_recurring(id).await.context(
+                                "couldn't reschedule recurring task",
+                            )?;
+                        } else {
+                            // The task's worker reported a plain failure; give
+                            // it another attempt (or let it fail for good) the
+                            // same way we treat interrupted tasks.
+                            self.retry_or_fail(id, Utc::now())
+                                .await
+                                .context("couldn't retry failed task")?;
+                        }
                     }
                 },
 
                 WakeupReason::GotTask(task_id) => {
-                    let Some(worker_id) = self.workers.choose_idling() else {
-                        self.tasks.push(task_id, None, Utc::now());
-                        self.tasks.pause();
+                    // We need the task's requirements to pick a compatible
+                    // worker, so fetch it before choosing.
+                    let task =
+                        Database::find_task(&self.database, task_id).await?;
+
+                    let Some(worker_id) = self.workers.choose_idling_for(
+                        &task.requires,
+                        &task.queue,
+                        &task.def.task_type,
+                    ) else {
+                        // No idle worker can run *this* task's type right now, so
+                        // park it aside keyed by that type. Crucially we don't
+                        // pause the whole scheduler - a backlog of one type must
+                        // not hold up dispatchable tasks of another. The parked
+                        // tasks come back into play the next time a worker frees
+                        // up (`resume`).
+                        self.tasks.park(
+                            task_id,
+                            task.scheduled_at,
+                            task.priority,
+                            task.created_at,
+                            task.def.task_type,
+                        );
                         continue;
                     };
 
@@ -121,6 +229,18 @@ impl Supervisor {
                             .await?;
 
                         tx.commit().await?;
+
+                        // Remember which task this worker is holding, so we can
+                        // reclaim it if the worker dies mid-execution, and arm
+                        // its execution-timeout deadline (if any).
+                        let deadline = task
+                            .timeout_secs
+                            .and_then(|secs| u64::try_from(secs).ok())
+                            .map(|secs| {
+                                Instant::now() + Duration::from_secs(secs)
+                            });
+
+                        self.workers.assign(worker_id, task_id, deadline);
                     } else {
                         // If we hit this branch, then the task either:
                         //
@@ -159,11 +279,270 @@ impl Supervisor {
                     }
                 }
 
+                WakeupReason::TaskTimedOut => {
+                    let now = Instant::now();
+
+                    // Tell each worker whose task overran to drop it, then
+                    // re-arm the task through the regular retry path.
+                    for (worker_id, task_id) in
+                        self.workers.reap_timed_out(now)
+                    {
+                        info!(?task_id, ?worker_id, "task timed out");
+
+                        WorkerNotification::CancelTask { id: task_id }
+                            .send(&self.database, worker_id)
+                            .await?;
+
+                        self.retry_or_fail(task_id, Utc::now())
+                            .await
+                            .with_context(|| {
+                                format!(
+                                    "couldn't reclaim timed-out task {}",
+                                    task_id.get()
+                                )
+                            })?;
+                    }
+
+                    self.tasks.resume(Utc::now());
+                }
+
                 WakeupReason::MaintenanceTime => {
-                    self.workers.gc(Utc::now());
+                    let now = Utc::now();
+
+                    // In-memory reclamation for workers we've been tracking...
+                    let mut reclaimed: BTreeSet<_> =
+                        self.workers.gc(now).into_iter().collect();
+
+                    // ...plus a database sweep that also catches workers which
+                    // died before this supervisor started (and whose tasks the
+                    // in-memory tracker therefore knows nothing about). The two
+                    // can overlap, hence the set.
+                    let cutoff = now
+                        - chrono::Duration::from_std(HEARTBEAT_TIMEOUT)
+                            .unwrap_or_else(|_| chrono::Duration::zero());
+
+                    reclaimed.extend(
+                        Database::reap_stale_workers(
+                            &self.database,
+                            cutoff,
+                            now,
+                        )
+                        .await
+                        .context("couldn't reap stale workers")?,
+                    );
+
+                    for task_id in reclaimed {
+                        self.retry_or_fail(task_id, now).await.with_context(
+                            || {
+                                format!(
+                                    "couldn't reclaim task {}",
+                                    task_id.get()
+                                )
+                            },
+                        )?;
+                    }
+
+                    // Separately, re-pend tasks a worker was handed but never
+                    // acknowledged - those aren't failures, they just need to go
+                    // back on the queue for someone else to pick up.
+                    let ack_cutoff = now
+                        - chrono::Duration::from_std(DISPATCH_ACK_TIMEOUT)
+                            .unwrap_or_else(|_| chrono::Duration::zero());
+
+                    let stuck = Database::reap_stuck_dispatched(
+                        &self.database,
+                        ack_cutoff,
+                        now,
+                    )
+                    .await
+                    .context("couldn't reap unacknowledged dispatches")?;
+
+                    let had_stuck = !stuck.is_empty();
+
+                    for (id, scheduled_at, priority, created_at) in stuck {
+                        info!(?id, "re-pending unacknowledged dispatch");
+
+                        self.tasks
+                            .push(id, scheduled_at, priority, created_at, now);
+                    }
+
+                    if had_stuck {
+                        self.tasks.resume(now);
+                    }
                 }
+
+                WakeupReason::ClockSample => {
+                    let now = Utc::now();
+
+                    // Our tasks' deadlines are monotonic `Instant`s, so a jump
+                    // in the wall clock leaves them pointing at the wrong time -
+                    // rebuild them all and kick the scheduler so anything that
+                    // became due during a forward jump dispatches right away.
+                    if let Some(drift) = self.clock.sample(now) {
+                        info!(?drift, "detected system clock jump, re-arming tasks");
+
+                        self.tasks.rearm(now);
+                        self.tasks.resume(now);
+                    }
+                }
+
+                WakeupReason::RetentionSweep => {
+                    if let Some((statuses, after)) =
+                        self.retention_mode.prune_spec()
+                    {
+                        let before = Utc::now()
+                            - chrono::Duration::from_std(after)
+                                .unwrap_or_else(|_| chrono::Duration::zero());
+
+                        let pruned = Database::prune_tasks(
+                            &self.database,
+                            before,
+                            statuses,
+                        )
+                        .await
+                        .context("couldn't prune terminal tasks")?;
+
+                        if pruned > 0 {
+                            info!(pruned, "pruned terminal tasks");
+                        }
+                    }
+                }
+            }
+
+            // If the window drained or evicted a task, top it back up from the
+            // database so we never starve while there's still work pending.
+            if self.tasks.take_refill_request() {
+                let window = Database::get_pending_window(
+                    &self.database,
+                    TASK_WINDOW as i64,
+                )
+                .await
+                .context("couldn't refill task window")?;
+
+                self.tasks.refill(window, Utc::now());
+            }
+        }
+    }
+
+    /// Routes an interrupted (or otherwise failed) task through the retry
+    /// policy: if it still has attempts left we re-arm it in `PendingTasks`
+    /// with a delay from its `retry_policy` (see `Task::retry_backoff`),
+    /// otherwise we let it land in the
+    /// terminal `failed` state.
+    async fn retry_or_fail(
+        &mut self,
+        id: TaskId,
+        now: DateTime<Utc>,
+    ) -> Result<()> {
+        let mut tx = self.database.begin().await?;
+
+        Database::interrupt_task(&mut *tx, id, now).await?;
+
+        let task = Database::find_task(&mut *tx, id).await?;
+
+        if task.attempts < task.max_retries {
+            let attempt = task.attempts + 1;
+            let backoff = task.retry_backoff(attempt);
+            let scheduled_at = now
+                + chrono::Duration::from_std(backoff)
+                    .unwrap_or_else(|_| chrono::Duration::zero());
+
+            info!(?id, attempt, ?backoff, "retrying interrupted task");
+
+            Database::retry_task(&mut *tx, id, attempt, scheduled_at, now)
+                .await?;
+
+            tx.commit().await?;
+
+            self.tasks.push(
+                id,
+                Some(scheduled_at),
+                task.priority,
+                task.created_at,
+                now,
+            );
+        } else {
+            info!(?id, "task exhausted its retries, marking as failed");
+
+            Database::complete_task(&mut *tx, id, false, now).await?;
+
+            tx.commit().await?;
+
+            // A recurring task that failed for good still owes its series the
+            // next occurrence - otherwise one bad run would silently stop the
+            // whole schedule. This is a no-op for one-shot tasks.
+            self.reschedule_recurring(id).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Re-enqueues the next occurrence of a recurring task once the current one
+    /// has finished.
+    ///
+    /// We compute the next fire time relative to `now` (and not to the task's
+    /// original `scheduled_at`), so a supervisor that was down past a couple of
+    /// fire times simply catches up to the closest upcoming slot instead of
+    /// backfilling every window it missed.
+    async fn reschedule_recurring(&mut self, id: TaskId) -> Result<()> {
+        let task = Database::find_task(&self.database, id).await?;
+
+        let now = Utc::now();
+
+        // A broken cron string mustn't take the whole supervisor down with it -
+        // we ingest-validate schedules at `create_task`, but a task predating
+        // that check (or hand-edited in the database) could still carry garbage.
+        // Log it and leave the series dormant rather than propagating the error
+        // up into `start` and crash-looping the loop.
+        let next = match task.next_occurrence(now) {
+            Ok(Some(next)) => next,
+            Ok(None) => return Ok(()),
+            Err(err) => {
+                warn!(?id, "skipping recurring task with an invalid schedule: {:?}", err);
+
+                return Ok(());
             }
+        };
+
+        info!(?id, %next, "rescheduling recurring task");
+
+        let new_id = Database::create_task(
+            &self.database,
+            task.def,
+            task.priority,
+            task.requires,
+            task.queue,
+            task.schedule,
+            task.series_id,
+            task.max_retries,
+            task.retry_policy,
+            task.retry_base_secs,
+            task.retry_max_backoff_secs,
+            task.timeout_secs,
+            // Carry the dedup hash forward so a fresh occurrence can't overlap
+            // one that's still in flight from a previous fire.
+            task.uniq_hash,
+            now,
+            Some(next),
+        )
+        .await?;
+
+        self.tasks.push(new_id, Some(next), task.priority, now, now);
+
+        Ok(())
+    }
+
+    /// Catches up recurring series that lost their outstanding instance while
+    /// the supervisor was offline (e.g. it crashed right after a task finished
+    /// but before rescheduling it); see `Database::find_due_recurring`.
+    async fn rearm_recurring(&mut self) -> Result<()> {
+        for id in Database::find_due_recurring(&self.database).await? {
+            info!(?id, "re-arming stalled recurring task");
+
+            self.reschedule_recurring(id).await?;
         }
+
+        Ok(())
     }
 
     /// Some tasks could got published when the supervisor was offline - this
@@ -171,18 +550,117 @@ impl Supervisor {
     async fn process_backlog(&mut self) -> Result<()> {
         let tasks = Database::get_backlog(&self.database).await?;
 
-        for (id, scheduled_at) in tasks {
+        for (id, scheduled_at, priority, created_at) in tasks {
             info!(?id, "task was created while supervisor was shut down");
 
-            self.tasks.push(id, scheduled_at, Utc::now());
+            self.tasks
+                .push(id, scheduled_at, priority, created_at, Utc::now());
         }
 
         Ok(())
     }
+
+    /// Sleeps until `at`, or forever when there's no deadline armed - used to
+    /// make the timeout branch of the main `select!` a no-op when no task is
+    /// currently running under a timeout.
+    async fn sleep_until(at: Option<Instant>) {
+        match at {
+            Some(at) => time::sleep_until(at).await,
+            None => std::future::pending().await,
+        }
+    }
 }
 
 enum WakeupReason<A, B> {
     GotNotification(A),
     GotTask(B),
+    TaskTimedOut,
     MaintenanceTime,
+    ClockSample,
+    RetentionSweep,
+}
+
+/// How aggressively the supervisor reclaims terminal tasks from the database.
+///
+/// The default is `KeepAll`, preserving the crate's original behaviour of never
+/// pruning; operators opt into pruning when table growth starts to hurt
+/// `find_tasks`/`get_backlog`. Tasks a caller soft-deleted via
+/// `Database::delete_task` stay on disk regardless - that path is for
+/// auditability, this one for throughput.
+#[derive(Clone, Copy, Debug, Default)]
+pub enum RetentionMode {
+    /// Never prune - terminal tasks accumulate forever.
+    #[default]
+    KeepAll,
+
+    /// Hard-delete `succeeded` tasks older than `after`, keeping failures
+    /// around for inspection.
+    RemoveSucceeded { after: Duration },
+
+    /// Hard-delete every terminal task (`succeeded`/`failed`) older than
+    /// `after`.
+    RemoveTerminal { after: Duration },
+}
+
+impl RetentionMode {
+    /// The statuses to prune and the minimum age, or `None` when this mode
+    /// keeps everything.
+    fn prune_spec(self) -> Option<(&'static [TaskStatus], Duration)> {
+        match self {
+            RetentionMode::KeepAll => None,
+
+            RetentionMode::RemoveSucceeded { after } => {
+                Some((&[TaskStatus::Succeeded], after))
+            }
+
+            RetentionMode::RemoveTerminal { after } => Some((
+                &[TaskStatus::Succeeded, TaskStatus::Failed],
+                after,
+            )),
+        }
+    }
+}
+
+/// Watches for the OS wall clock jumping relative to the monotonic clock, so the
+/// supervisor can refresh the pending tasks' deadlines (see `PendingTasks`).
+///
+/// We can't observe a jump directly, so we anchor a `(Instant, DateTime<Utc>)`
+/// baseline and, on each sample, compare how much wall-clock time elapsed
+/// against how much monotonic time elapsed - a healthy clock keeps the two in
+/// lockstep, a jump shows up as the difference.
+#[derive(Debug)]
+struct ClockMonitor {
+    interval: Interval,
+    baseline: (Instant, DateTime<Utc>),
+}
+
+impl ClockMonitor {
+    fn new() -> Self {
+        Self {
+            interval: time::interval(CLOCK_SAMPLE_INTERVAL),
+            baseline: (Instant::now(), Utc::now()),
+        }
+    }
+
+    async fn tick(&mut self) {
+        self.interval.tick().await;
+    }
+
+    /// Re-anchors the baseline and, if the wall clock drifted past
+    /// `CLOCK_JUMP_THRESHOLD` since the last sample, returns by how much.
+    fn sample(&mut self, now: DateTime<Utc>) -> Option<chrono::Duration> {
+        let (baseline_instant, baseline_utc) = self.baseline;
+
+        let monotonic = Instant::now().duration_since(baseline_instant);
+        let expected = baseline_utc
+            + chrono::Duration::from_std(monotonic)
+                .unwrap_or_else(|_| chrono::Duration::zero());
+
+        let drift = now - expected;
+
+        self.baseline = (Instant::now(), now);
+
+        (drift.abs().to_std().unwrap_or_default() >= CLOCK_JUMP_THRESHOLD)
+            .then_some(drift)
+    }
 }
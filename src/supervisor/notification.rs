@@ -1,4 +1,4 @@
-use crate::task::TaskId;
+use crate::task::{TaskId, WorkerLabels, WorkerQueues, WorkerTaskTypes};
 use crate::worker::{WorkerId, WorkerStatus};
 use anyhow::Result;
 use chrono::{DateTime, Utc};
@@ -19,6 +19,21 @@ pub enum SupervisorNotification {
         // otherwise a freshly-started supervisor wouldn't know whether the node
         // is busy or idling
         status: WorkerStatus,
+
+        // Likewise, the worker's capability labels ride along so the supervisor
+        // can match tasks against them without a separate registration step.
+        #[serde(default)]
+        labels: WorkerLabels,
+
+        // ...as do the queues the worker services, so tasks only ever reach a
+        // worker subscribed to their queue.
+        #[serde(default)]
+        queues: WorkerQueues,
+
+        // ...and the task types the worker has registered, so a job is only
+        // routed to a worker that knows how to build and run it.
+        #[serde(default)]
+        task_types: WorkerTaskTypes,
     },
 
     WorkerIdle {
@@ -27,8 +42,19 @@ pub enum SupervisorNotification {
 
     TaskCreated {
         id: TaskId,
+        priority: i32,
+        created_at: DateTime<Utc>,
         scheduled_at: Option<DateTime<Utc>>,
     },
+
+    /// A worker finished running a task. On success the supervisor reschedules
+    /// the next occurrence of a recurring task; on failure it routes the task
+    /// through the retry path (re-pending it with a backoff, or letting it land
+    /// in `failed` once the attempts are exhausted).
+    TaskCompleted {
+        id: TaskId,
+        succeeded: bool,
+    },
 }
 
 impl SupervisorNotification {
@@ -2,7 +2,7 @@ use crate::task::TaskId;
 use chrono::{DateTime, Utc};
 use futures::FutureExt;
 use std::cmp::Ordering;
-use std::collections::BinaryHeap;
+use std::collections::{BTreeMap, BinaryHeap};
 use std::future::Future;
 use std::pin::Pin;
 use std::task::{Context, Poll, Waker};
@@ -20,15 +20,39 @@ use tokio::time::{self, Instant, Sleep};
 ///
 ///      (other solutions will be probably more OS-dependent)
 ///
-/// N.B. there's no need to keep all tasks in the memory - in principle we could
-///      just keep the `n` closest tasks or even just *the* closest task and
-///      simply query database for a new task after dispatching one, it's just a
-///      different trade-off
+/// When built with a `capacity` (see `new`), we keep only the `capacity` soonest-due tasks in memory - a push that's *later* than
+/// the current horizon (the watermark) is simply dropped, staying in the
+/// database to come back through `refill`. After `poll` pops a task we raise a
+/// refill request so the supervisor refetches the next batch of soonest-due
+/// tasks from Postgres (`Database::get_pending_window`). A capacity of `None`
+/// (the `Default`) keeps every task in memory.
 #[derive(Debug, Default)]
 pub struct PendingTasks {
     tasks: BinaryHeap<PendingTask>,
     is_active: bool,
 
+    /// Tasks we popped but couldn't dispatch because no idle worker services
+    /// their type right now, set aside keyed by that `task_type`. Keeping them
+    /// out of the heap - instead of pausing the whole scheduler - means a
+    /// backlog of one type can't starve dispatchable tasks of another. They go
+    /// back into the heap on the next `resume` (i.e. whenever a worker frees up).
+    parked: BTreeMap<String, Vec<ParkedTask>>,
+
+    /// Soft cap on how many tasks we keep in memory; `None` means unbounded.
+    capacity: Option<usize>,
+
+    /// Deadline of the latest-due task we're currently retaining, kept up to
+    /// date whenever the window is full. `None` means either the window isn't
+    /// full yet or its latest task has no deadline (i.e. is due immediately) -
+    /// `within_window` disambiguates the two via the capacity check.
+    watermark: Option<Instant>,
+
+    /// Set once the window pops a task (`poll`) or evicts one over capacity, so
+    /// the supervisor knows to refetch the next soonest-due batch from the
+    /// database. A push rejected as beyond the watermark doesn't set it.
+    /// Bounded windows only.
+    needs_refill: bool,
+
     // N.B. I think we don't really have to have a waker here, because we'll get
     //      woken up anyway as a part of the supervisor's main `select!`, but
     //      won't hurt to keep a waker just in case
@@ -36,35 +60,213 @@ pub struct PendingTasks {
 }
 
 impl PendingTasks {
+    /// Builds a memory-bounded window retaining at most `capacity` tasks; see
+    /// the type-level docs. Use `Default` for an unbounded window.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: Some(capacity),
+            ..Default::default()
+        }
+    }
+
     pub fn push(
         &mut self,
         id: TaskId,
         scheduled_at: Option<DateTime<Utc>>,
+        priority: i32,
+        created_at: DateTime<Utc>,
         now: DateTime<Utc>,
     ) {
-        let at = scheduled_at
-            .and_then(|at| (at - now).to_std().ok())
-            .map(|at| Box::pin(time::sleep(at)));
+        // Beyond the horizon of a full window - leave it in the database, we'll
+        // pick it up on a later refill.
+        if !self.within_window(scheduled_at, now) {
+            return;
+        }
+
+        let at = Self::sleep_until(scheduled_at, now);
 
-        self.tasks.push(PendingTask { at, id });
+        self.tasks.push(PendingTask {
+            at,
+            scheduled_at,
+            id,
+            priority,
+            created_at,
+        });
+
+        self.enforce_capacity();
 
         if let Some(waker) = self.waker.take() {
             waker.wake();
         }
     }
 
-    /// Pauses the component so that we'll always return `Poll::Pending` until
-    /// someone resumes us.
+    /// Refills the window with a freshly-fetched batch of soonest-due tasks
+    /// (see `Database::get_pending_window`), clearing the outstanding refill
+    /// request. Tasks already held are re-pushed harmlessly - dispatch is
+    /// idempotent - and any overflow is trimmed back to the capacity.
+    pub fn refill<I>(&mut self, tasks: I, now: DateTime<Utc>)
+    where
+        I: IntoIterator<Item = (TaskId, Option<DateTime<Utc>>, i32, DateTime<Utc>)>,
+    {
+        self.needs_refill = false;
+
+        for (id, scheduled_at, priority, created_at) in tasks {
+            self.push(id, scheduled_at, priority, created_at, now);
+        }
+    }
+
+    /// Whether the window dropped a task and wants the supervisor to refetch the
+    /// next soonest-due batch. Consuming: the flag is cleared on read.
+    pub fn take_refill_request(&mut self) -> bool {
+        std::mem::take(&mut self.needs_refill)
+    }
+
+    /// Rebuilds every pending task's `Sleep` from its absolute `scheduled_at`
+    /// relative to `now`.
+    ///
+    /// The deadlines are monotonic `Instant`s derived once at `push` time from
+    /// `scheduled_at - now`, so they silently go stale if the wall clock jumps
+    /// (NTP step, suspend/resume, a manual change). The supervisor's clock
+    /// monitor calls this after it spots such a jump so a task that became due
+    /// during a forward jump fires right away.
+    pub fn rearm(&mut self, now: DateTime<Utc>) {
+        let tasks: Vec<_> = self
+            .tasks
+            .drain()
+            .map(|task| PendingTask {
+                at: Self::sleep_until(task.scheduled_at, now),
+                ..task
+            })
+            .collect();
+
+        self.tasks = BinaryHeap::from(tasks);
+
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+
+    /// Whether a task due at `scheduled_at` belongs in the in-memory window. An
+    /// unbounded (or not-yet-full) window accepts everything; a full one only
+    /// accepts tasks due no later than the current watermark.
+    fn within_window(
+        &self,
+        scheduled_at: Option<DateTime<Utc>>,
+        now: DateTime<Utc>,
+    ) -> bool {
+        let Some(capacity) = self.capacity else {
+            return true;
+        };
+
+        if self.tasks.len() < capacity {
+            return true;
+        }
+
+        // An unscheduled task is due immediately, so it always sorts inside the
+        // window.
+        let Some(candidate) = Self::deadline_at(scheduled_at, now) else {
+            return true;
+        };
+
+        // `watermark == None` here means the latest retained task has no
+        // deadline, i.e. the whole window is due immediately - a dated task is
+        // strictly later, so it stays out.
+        self.watermark
+            .is_some_and(|watermark| candidate <= watermark)
+    }
+
+    /// Trims the window back to its capacity (dropping the latest-due tasks) and
+    /// refreshes the watermark. A no-op for unbounded windows.
+    fn enforce_capacity(&mut self) {
+        let Some(capacity) = self.capacity else {
+            return;
+        };
+
+        if self.tasks.len() > capacity {
+            // Sort soonest-due first (the same order `poll` dispatches in), keep
+            // the leading `capacity` and let the rest fall back to the database.
+            let mut tasks: Vec<_> = self.tasks.drain().collect();
+
+            tasks.sort_by(|a, b| b.cmp(a));
+            tasks.truncate(capacity);
+
+            self.tasks = BinaryHeap::from(tasks);
+            self.needs_refill = true;
+        }
+
+        // Refresh the watermark - the latest-due deadline we're still holding.
+        // Only meaningful once we're actually full; until then we accept every
+        // push regardless. A window made up entirely of due-now tasks has no
+        // deadline to compare against, so its watermark stays `None` and dated
+        // tasks fall outside it.
+        self.watermark = (self.tasks.len() >= capacity)
+            .then(|| self.tasks.iter().filter_map(PendingTask::deadline).max())
+            .flatten();
+    }
+
+    /// Builds the `Sleep` for a task due at `scheduled_at` relative to `now`;
+    /// `None` when the task is already due (unscheduled or in the past).
+    fn sleep_until(
+        scheduled_at: Option<DateTime<Utc>>,
+        now: DateTime<Utc>,
+    ) -> Option<Pin<Box<Sleep>>> {
+        scheduled_at
+            .and_then(|at| (at - now).to_std().ok())
+            .map(|at| Box::pin(time::sleep(at)))
+    }
+
+    /// The `Instant` a task due at `scheduled_at` would fire at, without
+    /// actually arming a timer - used to weigh a task against the watermark.
+    fn deadline_at(
+        scheduled_at: Option<DateTime<Utc>>,
+        now: DateTime<Utc>,
+    ) -> Option<Instant> {
+        scheduled_at
+            .and_then(|at| (at - now).to_std().ok())
+            .map(|at| Instant::now() + at)
+    }
+
+    /// Sets a task aside because no idle worker can currently run its
+    /// `task_type`, keyed by that type. Unlike a blanket pause this leaves the
+    /// rest of the heap dispatchable, so type A's backlog doesn't stall type B.
     ///
-    /// Supervisor calls this function when all the workers are busy (or when
-    /// there's no workers present at all).
-    pub fn pause(&mut self) {
-        self.is_active = false;
+    /// The task rejoins the heap on the next `resume` - i.e. the next time a
+    /// worker's state changes and something might now be able to pick it up.
+    pub fn park(
+        &mut self,
+        id: TaskId,
+        scheduled_at: Option<DateTime<Utc>>,
+        priority: i32,
+        created_at: DateTime<Utc>,
+        task_type: String,
+    ) {
+        self.parked.entry(task_type).or_default().push(ParkedTask {
+            id,
+            scheduled_at,
+            priority,
+            created_at,
+        });
     }
 
-    pub fn resume(&mut self) {
+    pub fn resume(&mut self, now: DateTime<Utc>) {
         self.is_active = true;
 
+        // Give every parked task another shot now that the cluster has changed;
+        // any that still can't be placed will simply be parked again.
+        if !self.parked.is_empty() {
+            let parked = std::mem::take(&mut self.parked);
+
+            for task in parked.into_values().flatten() {
+                self.push(
+                    task.id,
+                    task.scheduled_at,
+                    task.priority,
+                    task.created_at,
+                    now,
+                );
+            }
+        }
+
         if let Some(waker) = self.waker.take() {
             waker.wake();
         }
@@ -102,6 +304,12 @@ impl Future for PendingTasks {
             // N.B. ideally we'd do it inside the `if let` above, but borrowck
             // complains
             self.tasks.pop();
+
+            // We just freed a slot, so for a bounded window ask the supervisor
+            // to refetch the next soonest-due batch and top us back up.
+            if self.capacity.is_some() {
+                self.needs_refill = true;
+            }
         }
 
         poll
@@ -112,10 +320,28 @@ impl Unpin for PendingTasks {
     //
 }
 
+/// A task temporarily held out of the heap by `PendingTasks::park`; it keeps
+/// just enough to be re-pushed verbatim once a worker frees up.
+#[derive(Debug)]
+struct ParkedTask {
+    id: TaskId,
+    scheduled_at: Option<DateTime<Utc>>,
+    priority: i32,
+    created_at: DateTime<Utc>,
+}
+
 #[derive(Debug)]
 struct PendingTask {
     at: Option<Pin<Box<Sleep>>>,
+
+    /// Absolute time this task is due; kept alongside the `Sleep` so we can
+    /// rebuild the (monotonic) deadline from scratch if the wall clock jumps -
+    /// see `PendingTasks::rearm`.
+    scheduled_at: Option<DateTime<Utc>>,
+
     id: TaskId,
+    priority: i32,
+    created_at: DateTime<Utc>,
 }
 
 impl PendingTask {
@@ -126,7 +352,7 @@ impl PendingTask {
 
 impl PartialEq for PendingTask {
     fn eq(&self, other: &Self) -> bool {
-        self.deadline() == other.deadline() && self.id == other.id
+        self.scheduled_at == other.scheduled_at && self.id == other.id
     }
 }
 
@@ -142,15 +368,23 @@ impl PartialOrd for PendingTask {
 
 impl Ord for PendingTask {
     fn cmp(&self, other: &Self) -> Ordering {
-        // TODO when a couple of tasks are scheduled on the same time, we will
-        //      dispatch them in accordance to their ids (so basically randomly,
-        //      considering that we use UUIDs) - so it would probably make more
-        //      sense to compare deadlines, then "created at"s, and only then
-        //      ids
-
+        // The heap is a max-heap and `poll` pops through `peek_mut`, so the
+        // "greatest" task is the one we dispatch first. We want that to be the
+        // task that's due the soonest, then - among tasks due at the same
+        // instant - the one with the highest priority, then the one created the
+        // earliest, and only as a last resort the one with the greatest id.
+        //
+        // We order on the absolute `scheduled_at`, *not* on the live `Sleep`
+        // deadline: `scheduled_at` is fixed for a task's whole stay in the heap,
+        // so its position never shifts as the timer elapses and the heap's
+        // ordering invariant holds. An unscheduled task (`None`) is due
+        // immediately, so it outranks any dated one (hence the flipped
+        // comparison: `None` sorts as the soonest).
         other
-            .deadline()
-            .cmp(&self.deadline())
+            .scheduled_at
+            .cmp(&self.scheduled_at)
+            .then_with(|| self.priority.cmp(&other.priority))
+            .then_with(|| other.created_at.cmp(&self.created_at))
             .then_with(|| self.id.cmp(&other.id))
     }
 }
@@ -174,11 +408,11 @@ mod tests {
 
         let now = dt("2018-01-01 12:00:00");
 
-        target.push(TaskId::from(1), Some(dt("2018-01-01 13:00:00")), now);
-        target.push(TaskId::from(2), None, now);
-        target.push(TaskId::from(3), Some(dt("2018-01-01 12:30:00")), now);
-        target.push(TaskId::from(4), Some(dt("2018-01-01 10:00:00")), now);
-        target.push(TaskId::from(5), None, now);
+        target.push(TaskId::from(1), Some(dt("2018-01-01 13:00:00")), 0, now, now);
+        target.push(TaskId::from(2), None, 0, now, now);
+        target.push(TaskId::from(3), Some(dt("2018-01-01 12:30:00")), 0, now, now);
+        target.push(TaskId::from(4), Some(dt("2018-01-01 10:00:00")), 0, now, now);
+        target.push(TaskId::from(5), None, 0, now, now);
 
         // ---
 
@@ -186,12 +420,14 @@ mod tests {
         // we report `Pending`
         assert_eq!(Poll::Pending, target.poll_unpin(&mut cx));
 
-        target.resume();
+        target.resume(now);
 
-        // T=12:00 - a couple of tasks were created without the deadline
+        // T=12:00 - tasks 5 and 2 have no deadline, so they're due immediately
+        // and outrank the already-overdue task 4 (due at 10:00); among the two
+        // undated ones the greater id breaks the tie.
         assert_eq!(Poll::Ready(TaskId::from(5)), target.poll_unpin(&mut cx));
-        assert_eq!(Poll::Ready(TaskId::from(4)), target.poll_unpin(&mut cx));
         assert_eq!(Poll::Ready(TaskId::from(2)), target.poll_unpin(&mut cx));
+        assert_eq!(Poll::Ready(TaskId::from(4)), target.poll_unpin(&mut cx));
         assert_eq!(Poll::Pending, target.poll_unpin(&mut cx));
 
         // T=12:31
@@ -210,4 +446,149 @@ mod tests {
         assert_eq!(Poll::Ready(TaskId::from(1)), target.poll_unpin(&mut cx));
         assert_eq!(Poll::Pending, target.poll_unpin(&mut cx));
     }
+
+    #[tokio::test]
+    async fn priority() {
+        let mut cx = Context::from_waker(Waker::noop());
+        let mut target = PendingTasks::default();
+
+        time::pause();
+
+        let now = dt("2018-01-01 12:00:00");
+        let earlier = dt("2018-01-01 11:00:00");
+
+        // All three tasks are ready at the same moment, so the tiebreak decides
+        // the order: first the priority (descending), then `created_at`
+        // (ascending)
+        target.push(TaskId::from(1), None, 0, now, now);
+        target.push(TaskId::from(2), None, 5, now, now);
+        target.push(TaskId::from(3), None, 5, earlier, now);
+
+        target.resume(now);
+
+        // 3 and 2 share the highest priority, but 3 was created earlier
+        assert_eq!(Poll::Ready(TaskId::from(3)), target.poll_unpin(&mut cx));
+        assert_eq!(Poll::Ready(TaskId::from(2)), target.poll_unpin(&mut cx));
+        assert_eq!(Poll::Ready(TaskId::from(1)), target.poll_unpin(&mut cx));
+        assert_eq!(Poll::Pending, target.poll_unpin(&mut cx));
+    }
+
+    #[tokio::test]
+    async fn rearm() {
+        let mut cx = Context::from_waker(Waker::noop());
+        let mut target = PendingTasks::default();
+
+        time::pause();
+
+        let now = dt("2018-01-01 12:00:00");
+
+        // A task due an hour out isn't ready yet
+        target.push(TaskId::from(1), Some(dt("2018-01-01 13:00:00")), 0, now, now);
+        target.resume(now);
+        assert_eq!(Poll::Pending, target.poll_unpin(&mut cx));
+
+        // The wall clock jumps forward past the task's due time; re-arming from
+        // the absolute `scheduled_at` makes it due immediately, even though the
+        // monotonic clock hasn't moved.
+        target.rearm(dt("2018-01-01 13:30:00"));
+        assert_eq!(Poll::Ready(TaskId::from(1)), target.poll_unpin(&mut cx));
+        assert_eq!(Poll::Pending, target.poll_unpin(&mut cx));
+    }
+
+    #[tokio::test]
+    async fn window_eviction() {
+        let mut cx = Context::from_waker(Waker::noop());
+        let mut target = PendingTasks::new(2);
+
+        time::pause();
+
+        let now = dt("2018-01-01 12:00:00");
+
+        // The window holds two tasks; the third is due *later* than the current
+        // horizon, so it's dropped from memory (it stays in the database and
+        // would come back via a refill).
+        target.push(TaskId::from(1), Some(dt("2018-01-01 12:10:00")), 0, now, now);
+        target.push(TaskId::from(2), Some(dt("2018-01-01 12:20:00")), 0, now, now);
+        target.push(TaskId::from(3), Some(dt("2018-01-01 12:30:00")), 0, now, now);
+
+        target.resume(now);
+
+        // T=12:11 - only the first task is due so far
+        time::advance(Duration::from_mins(11)).await;
+        assert_eq!(Poll::Ready(TaskId::from(1)), target.poll_unpin(&mut cx));
+
+        // Draining a task from a bounded window asks the supervisor to refetch
+        assert!(target.take_refill_request());
+        assert!(!target.take_refill_request());
+
+        // T=12:21
+        time::advance(Duration::from_mins(10)).await;
+        assert_eq!(Poll::Ready(TaskId::from(2)), target.poll_unpin(&mut cx));
+
+        // T=12:31 - task 3 was evicted, so nothing's left in memory
+        time::advance(Duration::from_mins(10)).await;
+        assert_eq!(Poll::Pending, target.poll_unpin(&mut cx));
+    }
+
+    #[tokio::test]
+    async fn window_preemption() {
+        let mut cx = Context::from_waker(Waker::noop());
+        let mut target = PendingTasks::new(2);
+
+        time::pause();
+
+        let now = dt("2018-01-01 12:00:00");
+
+        // Fill the window with two later tasks...
+        target.push(TaskId::from(1), Some(dt("2018-01-01 12:20:00")), 0, now, now);
+        target.push(TaskId::from(2), Some(dt("2018-01-01 12:30:00")), 0, now, now);
+
+        // ...then insert an earlier one: it belongs in the window and bumps out
+        // the latest-due task (task 2).
+        target.push(TaskId::from(3), Some(dt("2018-01-01 12:10:00")), 0, now, now);
+
+        target.resume(now);
+
+        // T=12:11 - the freshly-inserted task goes first
+        time::advance(Duration::from_mins(11)).await;
+        assert_eq!(Poll::Ready(TaskId::from(3)), target.poll_unpin(&mut cx));
+
+        // T=12:21
+        time::advance(Duration::from_mins(10)).await;
+        assert_eq!(Poll::Ready(TaskId::from(1)), target.poll_unpin(&mut cx));
+
+        // T=12:31 - task 2 was evicted by the preemption, so it never surfaces
+        time::advance(Duration::from_mins(10)).await;
+        assert_eq!(Poll::Pending, target.poll_unpin(&mut cx));
+    }
+
+    #[tokio::test]
+    async fn parking_doesnt_starve_other_types() {
+        let mut cx = Context::from_waker(Waker::noop());
+        let mut target = PendingTasks::default();
+
+        time::pause();
+
+        let now = dt("2018-01-01 12:00:00");
+
+        // Two due-now tasks of different types; task 1 has no worker for its
+        // type, task 2 does.
+        target.push(TaskId::from(1), None, 0, now, now);
+        target.push(TaskId::from(2), None, 0, now, now);
+
+        target.resume(now);
+
+        // The supervisor pops task 1, finds no worker for its type and parks it.
+        assert_eq!(Poll::Ready(TaskId::from(1)), target.poll_unpin(&mut cx));
+        target.park(TaskId::from(1), None, 0, now, "lonely".to_owned());
+
+        // Parking didn't stall the scheduler: task 2 still dispatches.
+        assert_eq!(Poll::Ready(TaskId::from(2)), target.poll_unpin(&mut cx));
+        assert_eq!(Poll::Pending, target.poll_unpin(&mut cx));
+
+        // Once a worker frees up, the parked task rejoins the heap.
+        target.resume(now);
+        assert_eq!(Poll::Ready(TaskId::from(1)), target.poll_unpin(&mut cx));
+        assert_eq!(Poll::Pending, target.poll_unpin(&mut cx));
+    }
 }
@@ -1,14 +1,36 @@
+use crate::task::{
+    TaskId, TaskRequirements, WorkerLabels, WorkerQueues, WorkerTaskTypes,
+};
 use crate::worker::{WorkerId, WorkerStatus};
 use crate::HEARTBEAT_TIMEOUT;
 use chrono::{DateTime, Utc};
 use rand::seq::IteratorRandom;
 use std::collections::{BTreeMap, BTreeSet};
+use tokio::time::Instant;
 use tracing::{info, warn};
 
 #[derive(Debug, Default)]
 pub struct SupervisedWorkers {
     workers: BTreeMap<WorkerId, SupervisedWorker>,
     idling_workers: BTreeSet<WorkerId>,
+
+    // Inverted indices over the *idling* workers, so that `choose_idling_for`
+    // doesn't have to scan every worker on each dispatch:
+    //
+    // - `label_index` maps an exact `key=value` label to the idling workers
+    //   that advertise it,
+    // - `key_index` maps a label key to the idling workers that advertise it
+    //   regardless of value.
+    label_index: BTreeMap<(String, String), BTreeSet<WorkerId>>,
+    key_index: BTreeMap<String, BTreeSet<WorkerId>>,
+
+    // Same idea, one step coarser: maps a queue name to the idling workers that
+    // service it, so a task only ever lands on a worker subscribed to its queue.
+    queue_index: BTreeMap<String, BTreeSet<WorkerId>>,
+
+    // ...and, finer, maps a task type to the idling workers that have registered
+    // it, so a job only lands on a worker that knows how to run it.
+    task_type_index: BTreeMap<String, BTreeSet<WorkerId>>,
 }
 
 impl SupervisedWorkers {
@@ -16,17 +38,15 @@ impl SupervisedWorkers {
         &mut self,
         id: WorkerId,
         status: WorkerStatus,
+        labels: WorkerLabels,
+        queues: WorkerQueues,
+        task_types: WorkerTaskTypes,
         now: DateTime<Utc>,
     ) {
-        let worker = SupervisedWorker { last_heard_at: now };
-
-        if self.workers.insert(id, worker).is_none() {
-            info!(?id, "worker joined the cluster");
-
-            if let WorkerStatus::Idle = status {
-                self.idling_workers.insert(id);
-            }
-        } else {
+        if let Some(worker) = self.workers.get_mut(&id) {
+            // Refresh the liveness timestamp without touching `assigned` - we
+            // must not lose track of the task the worker is currently holding.
+            //
             // Note that we care about the worker's status only during the first
             // heartbeat - that's because the worker has a race condition
             // between retrieving the Pg notification and updating the worker's
@@ -47,28 +67,218 @@ impl SupervisedWorkers {
             // If this happened, the supervisor could try to assign two tasks to
             // the same worker, which isn't the end of the world, but something
             // we are simply trying to avoid.
+            worker.last_heard_at = now;
+        } else {
+            info!(?id, "worker joined the cluster");
+
+            self.workers.insert(
+                id,
+                SupervisedWorker {
+                    last_heard_at: now,
+                    labels,
+                    queues,
+                    task_types,
+                    assigned: None,
+                },
+            );
+
+            if let WorkerStatus::Idle = status {
+                self.mark_as_idle(id);
+            }
         }
     }
 
-    pub fn mark_as_idle(&mut self, id: WorkerId) {
-        self.idling_workers.insert(id);
+    /// Records that `worker_id` is now running `task_id`, so that if the worker
+    /// dies mid-task we know which task to reclaim.
+    ///
+    /// `deadline` is the instant past which the task is considered to have
+    /// overrun its execution timeout (if it has one).
+    pub fn assign(
+        &mut self,
+        worker_id: WorkerId,
+        task_id: TaskId,
+        deadline: Option<Instant>,
+    ) {
+        if let Some(worker) = self.workers.get_mut(&worker_id) {
+            worker.assigned = Some(Assignment {
+                task: task_id,
+                deadline,
+            });
+        }
     }
 
-    pub fn choose_idling(&mut self) -> Option<WorkerId> {
-        let id = self
-            .idling_workers
+    /// Returns the soonest execution-timeout deadline across all running tasks,
+    /// so the supervisor's main loop can wake up exactly when one fires.
+    pub fn next_timeout(&self) -> Option<Instant> {
+        self.workers
+            .values()
+            .filter_map(|worker| worker.assigned.as_ref()?.deadline)
+            .min()
+    }
+
+    /// Collects the tasks whose execution timeout has elapsed as of `now`,
+    /// freeing the workers that held them so they can take on new work.
+    ///
+    /// Unlike `gc`, the workers themselves stay in the cluster - they're alive,
+    /// it's their *tasks* that overran.
+    pub fn reap_timed_out(&mut self, now: Instant) -> Vec<(WorkerId, TaskId)> {
+        let timed_out: Vec<_> = self
+            .workers
             .iter()
-            .choose(&mut rand::thread_rng())
-            .copied()?;
+            .filter_map(|(id, worker)| {
+                let assigned = worker.assigned.as_ref()?;
+                let deadline = assigned.deadline?;
+
+                (now >= deadline).then_some((*id, assigned.task))
+            })
+            .collect();
+
+        for (worker_id, _) in &timed_out {
+            self.mark_as_idle(*worker_id);
+        }
+
+        timed_out
+    }
+
+    pub fn mark_as_idle(&mut self, id: WorkerId) {
+        let Some(worker) = self.workers.get_mut(&id) else {
+            return;
+        };
 
-        self.idling_workers.remove(&id);
+        worker.assigned = None;
+
+        if self.idling_workers.insert(id) {
+            let labels = worker.labels.clone();
+            let queues = worker.queues.clone();
+            let task_types = worker.task_types.clone();
+
+            for (key, value) in labels {
+                self.label_index
+                    .entry((key.clone(), value))
+                    .or_default()
+                    .insert(id);
+
+                self.key_index.entry(key).or_default().insert(id);
+            }
+
+            for queue in queues {
+                self.queue_index.entry(queue).or_default().insert(id);
+            }
+
+            for task_type in task_types {
+                self.task_type_index
+                    .entry(task_type)
+                    .or_default()
+                    .insert(id);
+            }
+        }
+    }
+
+    /// Picks a random idle worker that services the task's `queue`, has
+    /// registered its `task_type`, and whose advertised labels satisfy *every*
+    /// one of the task's requirements, removing it from the idling pool.
+    ///
+    /// Returns `None` - leaving the task pending - when no idle worker
+    /// qualifies, rather than dispatching to a worker that can't run the task.
+    pub fn choose_idling_for(
+        &mut self,
+        requires: &TaskRequirements,
+        queue: &str,
+        task_type: &str,
+    ) -> Option<WorkerId> {
+        let by_queue = self.queue_index.get(queue).cloned().unwrap_or_default();
+
+        let by_type = self
+            .task_type_index
+            .get(task_type)
+            .cloned()
+            .unwrap_or_default();
+
+        let by_queue = &by_queue & &by_type;
+
+        let eligible = if requires.is_empty() {
+            by_queue
+        } else {
+            let mut sets = requires.iter().map(|(key, value)| match value {
+                Some(value) => self
+                    .label_index
+                    .get(&(key.clone(), value.clone()))
+                    .cloned()
+                    .unwrap_or_default(),
+
+                None => {
+                    self.key_index.get(key).cloned().unwrap_or_default()
+                }
+            });
+
+            let first = sets.next().unwrap_or_default();
+
+            sets.fold(first, |acc, set| &acc & &set) & &by_queue
+        };
+
+        let id = eligible.iter().choose(&mut rand::thread_rng()).copied()?;
+
+        self.remove_from_idling(id);
 
         Some(id)
     }
 
+    /// Drops a worker from the idling pool and every inverted index.
+    fn remove_from_idling(&mut self, id: WorkerId) {
+        if !self.idling_workers.remove(&id) {
+            return;
+        }
+
+        if let Some(worker) = self.workers.get(&id) {
+            for (key, value) in &worker.labels {
+                if let Some(ids) =
+                    self.label_index.get_mut(&(key.clone(), value.clone()))
+                {
+                    ids.remove(&id);
+
+                    if ids.is_empty() {
+                        self.label_index.remove(&(key.clone(), value.clone()));
+                    }
+                }
+
+                if let Some(ids) = self.key_index.get_mut(key) {
+                    ids.remove(&id);
+
+                    if ids.is_empty() {
+                        self.key_index.remove(key);
+                    }
+                }
+            }
+
+            for queue in &worker.queues {
+                if let Some(ids) = self.queue_index.get_mut(queue) {
+                    ids.remove(&id);
+
+                    if ids.is_empty() {
+                        self.queue_index.remove(queue);
+                    }
+                }
+            }
+
+            for task_type in &worker.task_types {
+                if let Some(ids) = self.task_type_index.get_mut(task_type) {
+                    ids.remove(&id);
+
+                    if ids.is_empty() {
+                        self.task_type_index.remove(task_type);
+                    }
+                }
+            }
+        }
+    }
+
     /// Garbage-collects workers, i.e. goes through all of them and removes
     /// those workers which we haven't heard from in a long time.
-    pub fn gc(&mut self, now: DateTime<Utc>) {
+    ///
+    /// Returns the tasks that were in-flight on the reaped workers, so the
+    /// supervisor can route them through the retry/interrupt path rather than
+    /// leaving them stranded.
+    pub fn gc(&mut self, now: DateTime<Utc>) -> Vec<TaskId> {
         let had_workers = !self.workers.is_empty();
 
         let dead_worker_ids: Vec<_> = self
@@ -86,14 +296,23 @@ impl SupervisedWorkers {
             })
             .collect();
 
+        let mut interrupted = Vec::new();
+
         for id in dead_worker_ids {
             warn!(?id, "worker seems to have died, cleaning up");
 
-            self.workers.remove(&id);
-            self.idling_workers.remove(&id);
+            self.remove_from_idling(id);
+            let worker = self.workers.remove(&id);
 
-            // TODO if this worker had any task assigned to it, mark that task
-            //      as "interrupted"
+            // If this worker had a task assigned to it, hand it back so it can
+            // be marked as "interrupted" and retried.
+            if let Some(assigned) = worker.and_then(|worker| worker.assigned) {
+                let task_id = assigned.task;
+
+                warn!(?id, ?task_id, "reclaiming task from dead worker");
+
+                interrupted.push(task_id);
+            }
         }
 
         if had_workers && self.workers.is_empty() {
@@ -103,19 +322,65 @@ impl SupervisedWorkers {
                 "tasks will not be dispatched until workers come back to life"
             );
         }
+
+        interrupted
     }
 }
 
 #[derive(Debug)]
 struct SupervisedWorker {
     last_heard_at: DateTime<Utc>,
+    labels: WorkerLabels,
+    queues: WorkerQueues,
+    task_types: WorkerTaskTypes,
+    assigned: Option<Assignment>,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Assignment {
+    task: TaskId,
+    deadline: Option<Instant>,
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::task::DEFAULT_QUEUE;
     use crate::test_utils::dt;
 
+    /// Builds a `WorkerLabels` map from `&[(key, value)]`.
+    fn labels(pairs: &[(&str, &str)]) -> WorkerLabels {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    /// Builds a `WorkerQueues` set from `&[name]`.
+    fn queues(names: &[&str]) -> WorkerQueues {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    /// The default queue, which most tests don't care to vary.
+    fn default_queues() -> WorkerQueues {
+        queues(&[DEFAULT_QUEUE])
+    }
+
+    /// Builds a `WorkerTaskTypes` set from `&[name]`.
+    fn task_types(names: &[&str]) -> WorkerTaskTypes {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    /// The built-in task type most tests route against when they don't care to
+    /// vary it.
+    const TASK_TYPE: &str = "foo";
+
+    /// A worker that can run the default [`TASK_TYPE`], for tests that only care
+    /// about the queue/label/liveness dimensions.
+    fn default_task_types() -> WorkerTaskTypes {
+        task_types(&[TASK_TYPE])
+    }
+
     #[test]
     fn choose_idling() {
         let mut target = SupervisedWorkers::default();
@@ -124,17 +389,167 @@ mod tests {
         let w2 = WorkerId::from(2);
         let w3 = WorkerId::from(3);
 
-        target.add(w1, WorkerStatus::Idle, now);
-        target.add(w2, WorkerStatus::Busy, now);
-        target.add(w3, WorkerStatus::Idle, now);
+        target.add(w1, WorkerStatus::Idle, labels(&[]), default_queues(), default_task_types(), now);
+        target.add(w2, WorkerStatus::Busy, labels(&[]), default_queues(), default_task_types(), now);
+        target.add(w3, WorkerStatus::Idle, labels(&[]), default_queues(), default_task_types(), now);
 
         for _ in 0..2 {
-            let actual = target.choose_idling().unwrap();
+            let actual = target
+                .choose_idling_for(&TaskRequirements::new(), DEFAULT_QUEUE, TASK_TYPE)
+                .unwrap();
 
             assert!(actual == w1 || actual == w3);
         }
 
-        assert!(target.choose_idling().is_none());
+        assert!(target
+            .choose_idling_for(&TaskRequirements::new(), DEFAULT_QUEUE, TASK_TYPE)
+            .is_none());
+    }
+
+    #[test]
+    fn choose_idling_for_capabilities() {
+        let mut target = SupervisedWorkers::default();
+        let now = dt("2018-01-01 12:00:00");
+        let cpu = WorkerId::from(1);
+        let gpu = WorkerId::from(2);
+
+        target.add(
+            cpu,
+            WorkerStatus::Idle,
+            labels(&[("arch", "cpu")]),
+            default_queues(),
+            default_task_types(),
+            now,
+        );
+        target.add(
+            gpu,
+            WorkerStatus::Idle,
+            labels(&[("arch", "gpu"), ("cuda", "12")]),
+            default_queues(),
+            default_task_types(),
+            now,
+        );
+
+        // Exact-match requirement only the gpu worker satisfies
+        let requires = TaskRequirements::from([(
+            "arch".to_string(),
+            Some("gpu".to_string()),
+        )]);
+
+        assert_eq!(Some(gpu), target.choose_idling_for(&requires, DEFAULT_QUEUE, TASK_TYPE));
+
+        // gpu is now busy, so the same requirement can't be met anymore
+        assert_eq!(None, target.choose_idling_for(&requires, DEFAULT_QUEUE, TASK_TYPE));
+
+        // A presence requirement the (still idle) cpu worker can't meet
+        let requires =
+            TaskRequirements::from([("cuda".to_string(), None)]);
+
+        assert_eq!(None, target.choose_idling_for(&requires, DEFAULT_QUEUE, TASK_TYPE));
+
+        // ...but an empty requirement set still lands on the cpu worker
+        assert_eq!(
+            Some(cpu),
+            target.choose_idling_for(&TaskRequirements::new(), DEFAULT_QUEUE, TASK_TYPE)
+        );
+    }
+
+    #[test]
+    fn choose_idling_for_queue() {
+        let mut target = SupervisedWorkers::default();
+        let now = dt("2018-01-01 12:00:00");
+        let general = WorkerId::from(1);
+        let reports = WorkerId::from(2);
+
+        target.add(
+            general,
+            WorkerStatus::Idle,
+            labels(&[]),
+            default_queues(),
+            default_task_types(),
+            now,
+        );
+        target.add(
+            reports,
+            WorkerStatus::Idle,
+            labels(&[]),
+            queues(&["reports"]),
+            default_task_types(),
+            now,
+        );
+
+        // Only the worker subscribed to `reports` is eligible for it...
+        assert_eq!(
+            Some(reports),
+            target.choose_idling_for(&TaskRequirements::new(), "reports", TASK_TYPE)
+        );
+
+        // ...and a queue nobody services leaves the task pending.
+        assert_eq!(
+            None,
+            target.choose_idling_for(&TaskRequirements::new(), "nope", TASK_TYPE)
+        );
+
+        // The default-queue worker is still idle and picks up default work.
+        assert_eq!(
+            Some(general),
+            target.choose_idling_for(&TaskRequirements::new(), DEFAULT_QUEUE, TASK_TYPE)
+        );
+    }
+
+    #[test]
+    fn choose_idling_for_task_type() {
+        let mut target = SupervisedWorkers::default();
+        let now = dt("2018-01-01 12:00:00");
+        let http = WorkerId::from(1);
+        let gpu = WorkerId::from(2);
+
+        target.add(
+            http,
+            WorkerStatus::Idle,
+            labels(&[]),
+            default_queues(),
+            task_types(&["send-email", "call-webhook"]),
+            now,
+        );
+        target.add(
+            gpu,
+            WorkerStatus::Idle,
+            labels(&[]),
+            default_queues(),
+            task_types(&["train-model"]),
+            now,
+        );
+
+        // A job only lands on a worker that registered its type...
+        assert_eq!(
+            Some(gpu),
+            target.choose_idling_for(
+                &TaskRequirements::new(),
+                DEFAULT_QUEUE,
+                "train-model"
+            )
+        );
+
+        // ...the http worker still picks up a type it advertises...
+        assert_eq!(
+            Some(http),
+            target.choose_idling_for(
+                &TaskRequirements::new(),
+                DEFAULT_QUEUE,
+                "send-email"
+            )
+        );
+
+        // ...and a type nobody registered leaves the task pending.
+        assert_eq!(
+            None,
+            target.choose_idling_for(
+                &TaskRequirements::new(),
+                DEFAULT_QUEUE,
+                "render-video"
+            )
+        );
     }
 
     #[test]
@@ -144,9 +559,9 @@ mod tests {
         let w2 = WorkerId::from(2);
         let w3 = WorkerId::from(3);
 
-        target.add(w1, WorkerStatus::Idle, dt("2018-01-01 12:00:06"));
-        target.add(w2, WorkerStatus::Idle, dt("2018-01-01 12:00:00"));
-        target.add(w3, WorkerStatus::Idle, dt("2018-01-01 12:00:12"));
+        target.add(w1, WorkerStatus::Idle, labels(&[]), default_queues(), default_task_types(), dt("2018-01-01 12:00:06"));
+        target.add(w2, WorkerStatus::Idle, labels(&[]), default_queues(), default_task_types(), dt("2018-01-01 12:00:00"));
+        target.add(w3, WorkerStatus::Idle, labels(&[]), default_queues(), default_task_types(), dt("2018-01-01 12:00:12"));
 
         target.gc(dt("2018-01-01 12:00:10"));
 
@@ -155,4 +570,22 @@ mod tests {
 
         assert_eq!(expected, actual);
     }
+
+    #[test]
+    fn gc_reclaims_assigned_tasks() {
+        let mut target = SupervisedWorkers::default();
+        let w1 = WorkerId::from(1);
+        let w2 = WorkerId::from(2);
+        let t1 = TaskId::from(10);
+
+        target.add(w1, WorkerStatus::Idle, labels(&[]), default_queues(), default_task_types(), dt("2018-01-01 12:00:00"));
+        target.add(w2, WorkerStatus::Idle, labels(&[]), default_queues(), default_task_types(), dt("2018-01-01 12:00:12"));
+
+        // w1 is holding a task and then dies, w2 stays alive and idle
+        target.assign(w1, t1, None);
+
+        let reclaimed = target.gc(dt("2018-01-01 12:00:10"));
+
+        assert_eq!(vec![t1], reclaimed);
+    }
 }
@@ -1,25 +1,129 @@
 use crate::worker::WorkerId;
-use anyhow::Result;
+use anyhow::{Context, Result};
+use async_trait::async_trait;
 use chrono::{DateTime, Utc};
+use cron::Schedule;
 use rand::Rng;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
 use sqlx::prelude::Type;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt::Write;
+use std::str::FromStr;
 use std::time::Duration;
 use tokio::time;
 use tracing::info;
 use uuid::Uuid;
 
+/// Worker properties a task requires in order to be dispatched to a given
+/// worker.
+///
+/// A value of `Some(v)` means the worker's label for that key must equal `v`
+/// (an exact match), while `None` means the worker merely has to advertise the
+/// key at all (a presence match).
+pub type TaskRequirements = BTreeMap<String, Option<String>>;
+
+/// Key/value labels a worker advertises, describing what it can run (e.g.
+/// `{ "arch": "gpu" }`).
+pub type WorkerLabels = BTreeMap<String, String>;
+
+/// The set of named queues a worker services - it only ever receives tasks
+/// whose `queue` is in this set.
+pub type WorkerQueues = BTreeSet<String>;
+
+/// The set of task types a worker can run, i.e. the keys it has registered on
+/// its [`Registry`] - it only ever receives tasks whose `def.task_type` is in
+/// this set.
+pub type WorkerTaskTypes = BTreeSet<String>;
+
+/// The queue a task lands on when the caller doesn't name one.
+pub const DEFAULT_QUEUE: &str = "default";
+
 #[derive(Clone, Debug, Serialize)]
 pub struct Task {
     pub id: TaskId,
-    pub def: TaskDef,
+    pub def: StoredTask,
     pub worker_id: Option<WorkerId>,
     pub status: TaskStatus,
+    pub priority: i32,
+    pub requires: TaskRequirements,
+    pub queue: String,
+    pub schedule: Option<String>,
+    pub series_id: Option<TaskId>,
+    pub attempts: i32,
+    pub max_retries: i32,
+    pub retry_policy: BackoffPolicy,
+    pub retry_base_secs: i64,
+    pub retry_max_backoff_secs: i64,
+    pub timeout_secs: Option<i64>,
+    pub uniq_hash: Option<String>,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
     pub scheduled_at: Option<DateTime<Utc>>,
 }
 
+impl Task {
+    /// Computes how long to wait before the next retry of this task, given the
+    /// attempt we're about to make (1-based).
+    ///
+    /// The raw delay follows this task's `retry_policy` (see `BackoffPolicy`),
+    /// is clamped to `retry_max_backoff_secs`, and then gets a random jitter in
+    /// `[0, backoff / 2)` on top so that a fleet of tasks interrupted at the
+    /// same moment doesn't stampede the workers all at once.
+    pub fn retry_backoff(&self, attempt: i32) -> Duration {
+        let base = self.retry_base_secs.max(0) as u64;
+        let max = self.retry_max_backoff_secs.max(0) as u64;
+
+        let attempt = attempt.max(1) as u32;
+
+        let raw = match self.retry_policy {
+            BackoffPolicy::Fixed => base,
+
+            BackoffPolicy::Linear => {
+                base.checked_mul(attempt as u64).unwrap_or(u64::MAX)
+            }
+
+            BackoffPolicy::Exponential => 1u64
+                .checked_shl(attempt - 1)
+                .and_then(|factor| base.checked_mul(factor))
+                .unwrap_or(u64::MAX),
+        };
+
+        let backoff = raw.min(max);
+
+        let jitter = if backoff > 1 {
+            rand::thread_rng().gen_range(0..backoff / 2)
+        } else {
+            0
+        };
+
+        Duration::from_secs(backoff + jitter)
+    }
+    /// For a recurring task (one that carries a cron `schedule`), returns the
+    /// next time it should fire strictly after `after`.
+    ///
+    /// Returns `Ok(None)` for a one-shot task, or for a schedule that has no
+    /// further occurrences. Note that we only ever look at the *next* upcoming
+    /// occurrence - if the supervisor was down for a while we don't want to
+    /// replay every slot we missed, just catch up to the closest future one.
+    pub fn next_occurrence(
+        &self,
+        after: DateTime<Utc>,
+    ) -> Result<Option<DateTime<Utc>>> {
+        let Some(schedule) = &self.schedule else {
+            return Ok(None);
+        };
+
+        let schedule = Schedule::from_str(schedule).with_context(|| {
+            format!("couldn't parse cron schedule: {}", schedule)
+        })?;
+
+        Ok(schedule.after(&after).next())
+    }
+}
+
 #[derive(
     Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize,
 )]
@@ -71,45 +175,380 @@ pub enum TaskStatus {
     Interrupted,
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+/// How the delay between a task's retries grows as the attempts pile up.
+///
+/// In every case the result is clamped to the task's `retry_max_backoff_secs`
+/// and jittered; see `Task::retry_backoff`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, Type)]
 #[serde(rename_all = "kebab-case")]
-#[serde(tag = "ty")]
-pub enum TaskDef {
-    Foo,
-    Bar,
-    Baz,
+#[sqlx(type_name = "backoff_policy", rename_all = "kebab-case")]
+pub enum BackoffPolicy {
+    /// A constant `retry_base_secs` regardless of the attempt.
+    Fixed,
+
+    /// Grows linearly - `retry_base_secs * attempt`.
+    Linear,
+
+    /// Doubles each attempt - `retry_base_secs * 2^(attempt - 1)`.
+    Exponential,
 }
 
-impl TaskDef {
-    pub async fn run(&self, ctxt: &TaskContext) -> Result<()> {
-        match self {
-            TaskDef::Foo => {
-                time::sleep(Duration::from_secs(3)).await;
+impl Default for BackoffPolicy {
+    fn default() -> Self {
+        Self::Exponential
+    }
+}
 
-                info!("Foo {}", ctxt.id.get());
-            }
+/// A persisted task's definition, as stored in the `def` JSON column: the
+/// `task_type` names a kind registered on a worker's [`Registry`] and `payload`
+/// is that kind's serialized form.
+///
+/// This replaces the old closed `TaskDef` enum so downstream crates can define
+/// their own jobs - the worker reconstructs a concrete [`Runnable`] from this
+/// pair at dispatch time instead of matching on a fixed set of variants.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StoredTask {
+    pub task_type: String,
+    pub payload: Value,
+}
 
-            TaskDef::Bar => {
-                let status =
-                    reqwest::get("https://www.whattimeisitrightnow.com")
-                        .await?
-                        .status();
+impl StoredTask {
+    /// Computes the uniqueness hash used to deduplicate tasks: a SHA-256 over
+    /// the serialized `(task_type, payload)`, optionally salted with a
+    /// user-supplied key so that otherwise-identical definitions can still be
+    /// told apart (or, the other way around, different definitions collapsed
+    /// together).
+    ///
+    /// See `Database::create_task`'s `on conflict` handling.
+    pub fn uniq_hash(&self, key: Option<&str>) -> Result<String> {
+        let mut hasher = Sha256::new();
 
-                info!("{}", status);
-            }
+        hasher.update(
+            serde_json::to_vec(self)
+                .context("couldn't serialize task definition")?,
+        );
 
-            TaskDef::Baz => {
-                let n = rand::thread_rng().gen_range(0..=343);
+        if let Some(key) = key {
+            hasher.update(b"\0");
+            hasher.update(key.as_bytes());
+        }
 
-                info!("Baz {}", n);
-            }
+        let mut hash = String::with_capacity(64);
+
+        for byte in hasher.finalize() {
+            write!(&mut hash, "{:02x}", byte)
+                .expect("writing to a String can't fail");
+        }
+
+        Ok(hash)
+    }
+}
+
+/// A unit of work a worker can run. Downstream crates implement this for their
+/// own job types and register them on a [`Registry`]; the built-in demo tasks
+/// (`foo`/`bar`/`baz`) are registered by [`Registry::with_builtins`].
+///
+/// `S` is the worker's shared application state - see [`TaskContext`].
+#[async_trait]
+pub trait Runnable<S>: Send + Sync {
+    /// The stable identifier persisted in `StoredTask::task_type` and matched
+    /// against a worker's advertised set when routing.
+    fn task_type(&self) -> &str;
+
+    async fn run(&self, ctxt: &TaskContext<S>) -> Result<()>;
+}
+
+/// Reconstructs a concrete [`Runnable`] from a stored payload.
+type Deserializer<S> =
+    Box<dyn Fn(Value) -> Result<Box<dyn Runnable<S>>> + Send + Sync>;
+
+/// Maps a `task_type` string back to the deserializer that rebuilds it, so a
+/// worker can run arbitrary user-registered jobs from their persisted form.
+pub struct Registry<S> {
+    deserializers: HashMap<String, Deserializer<S>>,
+}
+
+impl<S> Default for Registry<S> {
+    fn default() -> Self {
+        Self {
+            deserializers: HashMap::new(),
         }
+    }
+}
+
+impl<S: Send + Sync + 'static> Registry<S> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers `T` under `task_type`, so stored payloads carrying that tag
+    /// deserialize back into a `T` at dispatch time.
+    pub fn register<T>(&mut self, task_type: impl Into<String>)
+    where
+        T: Runnable<S> + DeserializeOwned + 'static,
+    {
+        self.deserializers.insert(
+            task_type.into(),
+            Box::new(|payload| {
+                let task: T = serde_json::from_value(payload)
+                    .context("couldn't deserialize task payload")?;
+
+                Ok(Box::new(task) as Box<dyn Runnable<S>>)
+            }),
+        );
+    }
+
+    /// Reconstructs the runnable for a stored task, erroring if its type was
+    /// never registered.
+    pub fn build(&self, stored: &StoredTask) -> Result<Box<dyn Runnable<S>>> {
+        let deserializer =
+            self.deserializers.get(&stored.task_type).with_context(|| {
+                format!("unknown task type: {}", stored.task_type)
+            })?;
+
+        deserializer(stored.payload.clone())
+    }
+
+    /// Whether `task_type` is known to this registry.
+    pub fn contains(&self, task_type: &str) -> bool {
+        self.deserializers.contains_key(task_type)
+    }
+
+    /// The set of registered `task_type`s - advertised by the worker in its
+    /// heartbeat (so the supervisor only routes jobs it can run) and handed to
+    /// the HTTP server (so it rejects jobs for kinds nothing knows how to run).
+    pub fn task_types(&self) -> WorkerTaskTypes {
+        self.deserializers.keys().cloned().collect()
+    }
+}
+
+impl Registry<reqwest::Client> {
+    /// A registry pre-populated with the crate's built-in demo tasks, so the
+    /// bundled worker keeps running `foo`/`bar`/`baz` out of the box. `Bar`
+    /// reaches for the pooled HTTP client, so the built-ins are tied to a
+    /// `reqwest::Client` state.
+    pub fn with_builtins() -> Self {
+        let mut registry = Self::new();
+
+        registry.register::<Foo>("foo");
+        registry.register::<Bar>("bar");
+        registry.register::<Baz>("baz");
+
+        registry
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Foo;
+
+#[async_trait]
+impl<S: Send + Sync> Runnable<S> for Foo {
+    fn task_type(&self) -> &str {
+        "foo"
+    }
+
+    async fn run(&self, ctxt: &TaskContext<S>) -> Result<()> {
+        time::sleep(Duration::from_secs(3)).await;
+
+        info!("Foo {}", ctxt.id.get());
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Bar;
+
+#[async_trait]
+impl Runnable<reqwest::Client> for Bar {
+    fn task_type(&self) -> &str {
+        "bar"
+    }
+
+    async fn run(&self, ctxt: &TaskContext<reqwest::Client>) -> Result<()> {
+        // Reuse the worker's pooled client instead of opening a fresh
+        // connection per run - that's the whole point of threading it in.
+        let status = ctxt
+            .state
+            .get("https://www.whattimeisitrightnow.com")
+            .send()
+            .await?
+            .status();
+
+        info!("{}", status);
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Baz;
+
+#[async_trait]
+impl<S: Send + Sync> Runnable<S> for Baz {
+    fn task_type(&self) -> &str {
+        "baz"
+    }
+
+    async fn run(&self, _ctxt: &TaskContext<S>) -> Result<()> {
+        let n = rand::thread_rng().gen_range(0..=343);
+
+        info!("Baz {}", n);
 
         Ok(())
     }
 }
 
+/// Everything a running task is handed: its own id plus the shared application
+/// state the worker was constructed with.
+///
+/// `S` is whatever the embedding application wants every task to see - an HTTP
+/// client, a config struct, a connection pool - cloned once per task. A worker
+/// that needs none of this is simply parameterized with `()`.
 #[derive(Clone, Debug)]
-pub struct TaskContext {
+pub struct TaskContext<S> {
     pub id: TaskId,
+    pub state: S,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A stored definition with an empty payload, for tests that don't care
+    /// about the payload itself.
+    fn stored(task_type: &str) -> StoredTask {
+        StoredTask {
+            task_type: task_type.to_owned(),
+            payload: Value::Null,
+        }
+    }
+
+    /// Builds a bare task carrying just the retry knobs the backoff cares
+    /// about; every other field is filled with an innocuous placeholder.
+    fn task_with(
+        policy: BackoffPolicy,
+        base: i64,
+        max: i64,
+    ) -> Task {
+        let now = Utc::now();
+
+        Task {
+            id: TaskId::from(0),
+            def: stored("foo"),
+            worker_id: None,
+            status: TaskStatus::Interrupted,
+            priority: 0,
+            requires: TaskRequirements::new(),
+            queue: DEFAULT_QUEUE.to_owned(),
+            schedule: None,
+            series_id: None,
+            attempts: 0,
+            max_retries: 5,
+            retry_policy: policy,
+            retry_base_secs: base,
+            retry_max_backoff_secs: max,
+            timeout_secs: None,
+            uniq_hash: None,
+            created_at: now,
+            updated_at: now,
+            scheduled_at: None,
+        }
+    }
+
+    /// Asserts the backoff lands in `[backoff, 1.5 * backoff]` - the jitter only
+    /// ever adds to the (clamped) base backoff, never subtracts.
+    fn assert_within(task: &Task, attempt: i32, backoff: u64) {
+        let actual = task.retry_backoff(attempt).as_secs();
+
+        assert!(
+            (backoff..=backoff + backoff / 2).contains(&actual),
+            "attempt {attempt}: {actual}s outside [{backoff}, {}]",
+            backoff + backoff / 2
+        );
+    }
+
+    #[test]
+    fn retry_backoff_follows_policy() {
+        // Fixed stays put regardless of the attempt.
+        let task = task_with(BackoffPolicy::Fixed, 4, 600);
+
+        for attempt in 1..=4 {
+            assert_within(&task, attempt, 4);
+        }
+
+        // Linear grows with the attempt - base * attempt.
+        let task = task_with(BackoffPolicy::Linear, 3, 600);
+
+        for attempt in 1..=4 {
+            assert_within(&task, attempt, 3 * attempt as u64);
+        }
+    }
+
+    #[test]
+    fn retry_backoff_doubles_and_clamps() {
+        let task = task_with(BackoffPolicy::Exponential, 2, 10);
+
+        // 2, 4, 8, then clamped to the 10s ceiling.
+        for (attempt, backoff) in [(1, 2), (2, 4), (3, 8), (4, 10), (5, 10)] {
+            assert_within(&task, attempt, backoff);
+        }
+    }
+
+    #[test]
+    fn next_occurrence_follows_schedule() {
+        let at = |s: &str| s.parse::<DateTime<Utc>>().unwrap();
+
+        let mut task = task_with(BackoffPolicy::Fixed, 1, 1);
+
+        // A one-shot task (no schedule) never fires again.
+        assert_eq!(None, task.next_occurrence(at("2018-01-01T00:00:00Z")).unwrap());
+
+        // Daily at noon - the next slot is strictly after `after`.
+        task.schedule = Some("0 0 12 * * *".to_owned());
+
+        assert_eq!(
+            Some(at("2018-01-01T12:00:00Z")),
+            task.next_occurrence(at("2018-01-01T00:00:00Z")).unwrap()
+        );
+
+        // Already past today's slot, so it rolls over to tomorrow.
+        assert_eq!(
+            Some(at("2018-01-02T12:00:00Z")),
+            task.next_occurrence(at("2018-01-01T12:00:00Z")).unwrap()
+        );
+    }
+
+    #[test]
+    fn uniq_hash_is_stable_and_salted() {
+        let foo = stored("foo").uniq_hash(None).unwrap();
+        let bar = stored("bar").uniq_hash(None).unwrap();
+
+        // A SHA-256 rendered as hex - deterministic, fixed-width...
+        assert_eq!(64, foo.len());
+        assert!(foo.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_eq!(foo, stored("foo").uniq_hash(None).unwrap());
+
+        // ...distinct definitions don't collide...
+        assert_ne!(foo, bar);
+
+        // ...and the optional namespace widens/narrows what counts as identical.
+        let keyed = stored("foo").uniq_hash(Some("tenant-1")).unwrap();
+
+        assert_ne!(foo, keyed);
+        assert_eq!(keyed, stored("foo").uniq_hash(Some("tenant-1")).unwrap());
+        assert_ne!(keyed, stored("foo").uniq_hash(Some("tenant-2")).unwrap());
+    }
+
+    #[test]
+    fn registry_builds_registered_types_only() {
+        let registry = Registry::<reqwest::Client>::with_builtins();
+
+        assert!(registry.contains("foo"));
+        assert!(registry.build(&stored("bar")).is_ok());
+
+        // An unregistered type is reported rather than silently dispatched.
+        assert!(!registry.contains("nope"));
+        assert!(registry.build(&stored("nope")).is_err());
+    }
 }
@@ -2,42 +2,63 @@ mod id;
 mod listener;
 mod notification;
 mod status;
+mod tranquilizer;
 mod watchdog;
 
 pub use self::id::*;
 use self::listener::*;
 pub use self::notification::*;
 pub use self::status::*;
+use self::tranquilizer::*;
 use self::watchdog::*;
 use crate::database::Database;
 use crate::supervisor::SupervisorNotification;
-use crate::task::{TaskContext, TaskId};
+use crate::task::{Registry, TaskContext, TaskId, WorkerLabels, WorkerQueues};
 use anyhow::{anyhow, Context, Result};
 use chrono::Utc;
 use sqlx::PgPool;
 use std::sync::Arc;
 use tokio::select;
 use tokio::sync::oneshot;
+use tokio::time;
 use tracing::info;
 
-#[derive(Debug)]
-pub struct Worker {
+pub struct Worker<S> {
     id: WorkerId,
     database: PgPool,
     listener: WorkerListener,
     status: Arc<AtomicWorkerStatus>,
     watchdog: oneshot::Receiver<WorkerWatchdogDied>,
+    registry: Registry<S>,
+    tranquilizer: Tranquilizer,
+    state: S,
 }
 
-impl Worker {
-    pub async fn new(database: &str, id: WorkerId) -> Result<Self> {
+impl<S> Worker<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    pub async fn new(
+        database: &str,
+        id: WorkerId,
+        labels: WorkerLabels,
+        queues: WorkerQueues,
+        registry: Registry<S>,
+        tranquility: f64,
+        state: S,
+    ) -> Result<Self> {
         let listener = WorkerListener::connect(database, id).await?;
         let database = Database::connect(database).await?;
         let status = Arc::new(AtomicWorkerStatus::default());
 
-        let watchdog = WorkerWatchdog::new(id, status.clone())
-            .spawn(database.clone())
-            .await?;
+        // The worker only advertises the task types it has actually registered,
+        // so the supervisor never routes a job here that we couldn't build.
+        let task_types = registry.task_types();
+
+        let watchdog =
+            WorkerWatchdog::new(id, status.clone(), labels, queues, task_types)
+                .spawn(database.clone())
+                .await?;
 
         Ok(Self {
             id,
@@ -45,6 +66,9 @@ impl Worker {
             listener,
             status,
             watchdog,
+            registry,
+            tranquilizer: Tranquilizer::new(tranquility),
+            state,
         })
     }
 
@@ -70,6 +94,12 @@ impl Worker {
                             format!("couldn't process task {}", id.get())
                         })?;
                     }
+
+                    WorkerNotification::CancelTask { id } => {
+                        // A cancellation for a task we're not running anymore
+                        // (it already finished) - nothing to do.
+                        info!(?id, "ignoring cancellation for a finished task");
+                    }
                 },
 
                 WakeupReason::WatchdogDied => {
@@ -84,22 +114,88 @@ impl Worker {
 
         info!(?id, "starting task");
 
-        let task = Database::begin_task(&self.database, id, Utc::now()).await?;
+        let stored = Database::begin_task(&self.database, id, Utc::now()).await?;
+        let task = self.registry.build(&stored).with_context(|| {
+            format!("couldn't build task {} of type {}", id.get(), stored.task_type)
+        })?;
+        let ctxt = TaskContext {
+            id,
+            state: self.state.clone(),
+        };
+
+        // Run the task while still listening for notifications, so the
+        // supervisor can cancel it mid-flight (e.g. when it overruns its
+        // execution timeout). We pin the future and only abort it on a matching
+        // `CancelTask` - any other notification is simply ignored and the task
+        // keeps running.
+        let started_at = time::Instant::now();
+
+        let run = task.run(&ctxt);
+        tokio::pin!(run);
+
+        let outcome: Option<Result<()>> = loop {
+            select! {
+                result = &mut run => break Some(result),
+                notif = self.listener.next() => match notif? {
+                    WorkerNotification::CancelTask { id: cancelled }
+                        if cancelled == id =>
+                    {
+                        break None;
+                    }
+
+                    _ => {
+                        // Not for us (or not a cancellation) - keep running.
+                    }
+                },
+            }
+        };
 
-        match task.run(&TaskContext { id }).await {
-            Ok(_) => {
+        // A cancelled task (the `None` arm) has already been re-armed by the
+        // supervisor, so there's nothing to throttle against - only rest after a
+        // task we actually ran to completion.
+        let ran = outcome.is_some();
+
+        match outcome {
+            Some(Ok(_)) => {
                 info!(?id, "task succeeded");
 
                 Database::complete_task(&self.database, id, true, Utc::now())
                     .await?;
+
+                SupervisorNotification::TaskCompleted {
+                    id,
+                    succeeded: true,
+                }
+                .send(&self.database)
+                .await?;
             }
 
-            Err(err) => {
+            Some(Err(err)) => {
                 info!(?id, "task failed: {:?}", err);
 
-                Database::complete_task(&self.database, id, false, Utc::now())
-                    .await?;
+                // We leave the task in `running` and let the supervisor decide
+                // whether to retry it or mark it `failed` - that keeps the retry
+                // policy in one place (`retry_or_fail`).
+                SupervisorNotification::TaskCompleted {
+                    id,
+                    succeeded: false,
+                }
+                .send(&self.database)
+                .await?;
             }
+
+            None => {
+                // The supervisor asked us to stop and has already re-armed the
+                // task on its side, so we just drop it and free ourselves up.
+                info!(?id, "task cancelled by supervisor");
+            }
+        }
+
+        // Rest for a proportion of the task's duration before declaring
+        // ourselves idle, throttling our own throughput (no-op unless the
+        // operator dialled in a `tranquility` factor).
+        if ran {
+            self.tranquilizer.tranquilize(started_at.elapsed()).await;
         }
 
         self.status.store(WorkerStatus::Idle);
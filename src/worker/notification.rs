@@ -11,6 +11,11 @@ use tracing::{instrument, trace};
 #[serde(tag = "ty")]
 pub enum WorkerNotification {
     TaskDispatched { id: TaskId },
+
+    /// Supervisor asks the worker to stop running a task - e.g. because it blew
+    /// past its execution timeout. Best-effort: the supervisor has already
+    /// freed the slot and re-armed the task on its side.
+    CancelTask { id: TaskId },
 }
 
 impl WorkerNotification {
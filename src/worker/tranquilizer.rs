@@ -0,0 +1,88 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+use tokio::time;
+use tracing::trace;
+
+/// How many recent task durations we average over when deciding how long to
+/// rest; enough to smooth over the odd spike without lagging behind a genuine
+/// change in workload.
+const WINDOW: usize = 8;
+
+/// A back-pressure knob borrowed from Garage: after each task the worker rests
+/// for a proportion of how long that task took, so a busy worker doesn't
+/// saturate whatever the tasks talk to downstream.
+///
+/// The rest is `tranquility * average_duration`, where the average is taken over
+/// a small moving window of recent durations so a single slow task doesn't send
+/// the worker to sleep for ages. A `tranquility` of `0` disables the whole thing
+/// and is the default.
+#[derive(Debug)]
+pub struct Tranquilizer {
+    tranquility: f64,
+    durations: VecDeque<Duration>,
+}
+
+impl Tranquilizer {
+    pub fn new(tranquility: f64) -> Self {
+        Self {
+            tranquility,
+            durations: VecDeque::with_capacity(WINDOW),
+        }
+    }
+
+    /// Records how long the just-finished task took and, unless throttling is
+    /// disabled, sleeps for `tranquility` times the windowed-average duration.
+    pub async fn tranquilize(&mut self, elapsed: Duration) {
+        if let Some(nap) = self.record(elapsed) {
+            trace!(?nap, "tranquilizing");
+
+            time::sleep(nap).await;
+        }
+    }
+
+    /// Pushes `elapsed` into the moving window and returns how long to rest, or
+    /// `None` when throttling is disabled. Kept separate from the sleep so it can
+    /// be unit-tested without a clock.
+    fn record(&mut self, elapsed: Duration) -> Option<Duration> {
+        if self.durations.len() == WINDOW {
+            self.durations.pop_front();
+        }
+
+        self.durations.push_back(elapsed);
+
+        if self.tranquility <= 0.0 {
+            return None;
+        }
+
+        let sum: Duration = self.durations.iter().sum();
+        let average = sum / self.durations.len() as u32;
+
+        Some(average.mul_f64(self.tranquility))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn disabled_when_tranquility_is_zero() {
+        let mut target = Tranquilizer::new(0.0);
+
+        assert_eq!(None, target.record(Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn rests_for_a_proportion_of_the_windowed_average() {
+        let mut target = Tranquilizer::new(0.5);
+
+        // A single 4s task -> rest for half of it.
+        assert_eq!(Some(Duration::from_secs(2)), target.record(Duration::from_secs(4)));
+
+        // A 2s follow-up averages to 3s, so the spike is smoothed out.
+        assert_eq!(
+            Some(Duration::from_millis(1500)),
+            target.record(Duration::from_secs(2))
+        );
+    }
+}
@@ -1,6 +1,7 @@
 use super::AtomicWorkerStatus;
 use crate::database::Database;
 use crate::supervisor::SupervisorNotification;
+use crate::task::{WorkerLabels, WorkerQueues, WorkerTaskTypes};
 use crate::worker::WorkerId;
 use crate::HEARTBEAT_DURATION;
 use anyhow::Result;
@@ -15,11 +16,26 @@ use tracing::{error, info};
 pub struct WorkerWatchdog {
     id: WorkerId,
     status: Arc<AtomicWorkerStatus>,
+    labels: WorkerLabels,
+    queues: WorkerQueues,
+    task_types: WorkerTaskTypes,
 }
 
 impl WorkerWatchdog {
-    pub fn new(id: WorkerId, status: Arc<AtomicWorkerStatus>) -> Self {
-        Self { id, status }
+    pub fn new(
+        id: WorkerId,
+        status: Arc<AtomicWorkerStatus>,
+        labels: WorkerLabels,
+        queues: WorkerQueues,
+        task_types: WorkerTaskTypes,
+    ) -> Self {
+        Self {
+            id,
+            status,
+            labels,
+            queues,
+            task_types,
+        }
     }
 
     pub async fn spawn(
@@ -68,6 +84,9 @@ impl WorkerWatchdog {
         SupervisorNotification::WorkerHeartbeat {
             id: self.id,
             status: self.status.load(),
+            labels: self.labels.clone(),
+            queues: self.queues.clone(),
+            task_types: self.task_types.clone(),
         }
         .send(db)
         .await?;